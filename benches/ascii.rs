@@ -0,0 +1,33 @@
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use lang_ast::{DefaultHandler, Lexer};
+use regex::Regex;
+
+fn lexer(src: &str, ascii: bool) -> Lexer<DefaultHandler> {
+    let mut lex = Lexer::new(src.to_string(), DefaultHandler);
+    lex.add_regex("id", Regex::new("[A-Za-z_][A-Za-z0-9_]*").unwrap());
+    lex.add_regex("num", Regex::new(r"\d+").unwrap());
+    lex.add_string("\"", "str");
+    lex.is_ascii = ascii;
+    lex
+}
+
+fn ascii_fast_path(c: &mut Criterion) {
+    let src = "total = (price_1 + 22) * \"tax\" ;\n".repeat(64);
+    let mut group = c.benchmark_group("tokenize");
+    group.bench_function("ascii", |b| b.iter(|| lexer(black_box(&src), true).tokenize().unwrap()));
+    group.bench_function("utf8", |b| b.iter(|| lexer(black_box(&src), false).tokenize().unwrap()));
+    group.finish();
+}
+
+/// long enough that any per-token cost growing with the source shows
+fn large_source(c: &mut Criterion) {
+    let src = "total = (price_1 + 22) * \"tax\" ;\n".repeat(20_000);
+    let mut group = c.benchmark_group("tokenize_large");
+    group.sample_size(10);
+    group.bench_function("ascii", |b| b.iter(|| lexer(black_box(&src), true).tokenize().unwrap()));
+    group.bench_function("utf8", |b| b.iter(|| lexer(black_box(&src), false).tokenize().unwrap()));
+    group.finish();
+}
+
+criterion_group!(benches, ascii_fast_path, large_source);
+criterion_main!(benches);
@@ -1,9 +1,30 @@
+use std::any::Any;
+use std::fmt::Debug;
 use std::ops::{Add, Sub};
+use std::sync::Arc;
 
+use crate::{LexToken, AstResult, AstError};
 
 
 
-#[derive(Clone, Debug)]
+
+/// A handler defined value, two are equal only when they share the same allocation.
+#[derive(Clone)]
+pub struct AstCustom(pub Arc<dyn Any + Send + Sync>);
+
+impl Debug for AstCustom {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("AstCustom")
+    }
+}
+
+impl PartialEq for AstCustom {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub enum AstAny {
     Bool(bool),
     U8(u8),
@@ -23,10 +44,106 @@ pub enum AstAny {
     Str(String),
     Char(char),
     Other(Vec<u8>),
+    List(Vec<AstAny>),
+    Custom(AstCustom),
     Unsport,
     Unknow,
 }
 
+/// What `AstAny::parse_int` does with a literal too big for `i64`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum IntOverflow {
+    /// fail with `AstError` holding the token
+    #[default]
+    Error,
+    /// widen to `I128`, or to `F64` past that
+    Promote,
+}
+
+impl AstAny {
+    /// wrap a domain value of the handler's own type
+    pub fn custom<T: Any + Send + Sync>(value: T) -> AstAny {
+        AstAny::Custom(AstCustom(Arc::new(value)))
+    }
+
+    /// the value stored by `custom` if it is a `T`
+    pub fn as_custom<T: Any>(&self) -> Option<&T> {
+        match self {
+            AstAny::Custom(v) => v.0.downcast_ref(),
+            _ => None,
+        }
+    }
+
+    /// Read an integer literal, `0x`/`0o`/`0b` prefixed or decimal with `_` separators and an
+    /// optional leading `-` or `+`, as `I64`.
+    pub fn parse_int(token: &LexToken, overflow: IntOverflow) -> AstResult<AstAny> {
+        let text = token.get_value();
+        let (negative, text) = match text.as_bytes().first() {
+            Some(b'-') => (true, &text[1..]),
+            Some(b'+') => (false, &text[1..]),
+            _ => (false, text),
+        };
+        let (radix, digits) = match text.get(..2) {
+            Some("0x") | Some("0X") => (16, &text[2..]),
+            Some("0o") | Some("0O") => (8, &text[2..]),
+            Some("0b") | Some("0B") => (2, &text[2..]),
+            _ => (10, text),
+        };
+        let digits: String = digits.chars().filter(|c| *c != '_').collect();
+        let bad = digits.find(|c: char| !c.is_digit(radix)).map(|at| &digits[at..]);
+        if digits.is_empty() || bad.is_some() {
+            // the std parser names the trouble, the `0` keeps it from reading a bad char as a sign
+            let probe = bad.map_or(String::new(), |rest| format!("0{}", rest));
+            return match u8::from_str_radix(&probe, radix) {
+                Err(err) => Err(err.into()),
+                Ok(_) => Err(AstError::new_unexpected_token_error(token.clone())),
+            };
+        }
+        let mut wide: Option<u128> = Some(0);
+        let mut float = 0f64;
+        for c in digits.chars() {
+            let d = c.to_digit(radix).unwrap();
+            wide = wide.and_then(|v| v.checked_mul(radix as u128)?.checked_add(d as u128));
+            float = float * radix as f64 + d as f64;
+        }
+        let wide = wide.and_then(|v| match negative {
+            true => (v <= i128::MAX as u128 + 1).then(|| (v as i128).wrapping_neg()),
+            false => i128::try_from(v).ok(),
+        });
+        match wide.map(|v| (i64::try_from(v), v)) {
+            Some((Ok(v), _)) => Ok(AstAny::I64(v)),
+            _ if overflow == IntOverflow::Error => Err(AstError::new_int_overflow_error(token.clone())),
+            Some((_, v)) => Ok(AstAny::I128(v)),
+            None => Ok(AstAny::F64(if negative { -float } else { float })),
+        }
+    }
+
+    pub fn is_truthy(&self) -> bool {
+        match self {
+            AstAny::Bool(v) => *v,
+            AstAny::U8(v) => *v != 0,
+            AstAny::I8(v) => *v != 0,
+            AstAny::U16(v) => *v != 0,
+            AstAny::I16(v) => *v != 0,
+            AstAny::U32(v) => *v != 0,
+            AstAny::I32(v) => *v != 0,
+            AstAny::U64(v) => *v != 0,
+            AstAny::I64(v) => *v != 0,
+            AstAny::U128(v) => *v != 0,
+            AstAny::I128(v) => *v != 0,
+            AstAny::Isize(v) => *v != 0,
+            AstAny::Usize(v) => *v != 0,
+            AstAny::F32(v) => *v != 0.0,
+            AstAny::F64(v) => *v != 0.0,
+            AstAny::Str(v) => !v.is_empty(),
+            AstAny::List(v) => !v.is_empty(),
+            AstAny::Other(v) => !v.is_empty(),
+            AstAny::Char(_) | AstAny::Custom(_) => true,
+            AstAny::Unsport | AstAny::Unknow => false,
+        }
+    }
+}
+
 impl Add for &AstAny {
     type Output = AstAny;
 
@@ -0,0 +1,293 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use crate::{Handler, Lexer, LexToken, AstAny, AstResult, AstError, TypeInterner};
+
+const MAGIC: &[u8] = b"LAST\x01";
+/// how deep `AstAny::List` values may nest in decoded bytes
+const MAX_VALUE_DEPTH: usize = 128;
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn uint(&mut self, mut v: u128) {
+        loop {
+            let byte = (v & 0x7f) as u8;
+            v >>= 7;
+            if v == 0 {
+                self.0.push(byte);
+                return;
+            }
+            self.0.push(byte | 0x80);
+        }
+    }
+
+    fn int(&mut self, v: i128) {
+        self.uint(((v << 1) ^ (v >> 127)) as u128);
+    }
+
+    fn bytes(&mut self, v: &[u8]) {
+        self.uint(v.len() as u128);
+        self.0.extend_from_slice(v);
+    }
+
+    fn value(&mut self, value: &AstAny) {
+        match value {
+            AstAny::Bool(v) => { self.uint(0); self.uint(*v as u128) },
+            AstAny::U8(v) => { self.uint(1); self.uint(*v as u128) },
+            AstAny::I8(v) => { self.uint(2); self.int(*v as i128) },
+            AstAny::U16(v) => { self.uint(3); self.uint(*v as u128) },
+            AstAny::I16(v) => { self.uint(4); self.int(*v as i128) },
+            AstAny::U32(v) => { self.uint(5); self.uint(*v as u128) },
+            AstAny::I32(v) => { self.uint(6); self.int(*v as i128) },
+            AstAny::U64(v) => { self.uint(7); self.uint(*v as u128) },
+            AstAny::I64(v) => { self.uint(8); self.int(*v as i128) },
+            AstAny::U128(v) => { self.uint(9); self.uint(*v) },
+            AstAny::I128(v) => { self.uint(10); self.int(*v) },
+            AstAny::Isize(v) => { self.uint(11); self.int(*v as i128) },
+            AstAny::Usize(v) => { self.uint(12); self.uint(*v as u128) },
+            AstAny::F32(v) => { self.uint(13); self.uint(v.to_bits() as u128) },
+            AstAny::F64(v) => { self.uint(14); self.uint(v.to_bits() as u128) },
+            AstAny::Str(v) => { self.uint(15); self.bytes(v.as_bytes()) },
+            AstAny::Char(v) => { self.uint(16); self.uint(*v as u128) },
+            AstAny::Other(v) => { self.uint(17); self.bytes(v) },
+            AstAny::List(v) => {
+                self.uint(18);
+                self.uint(v.len() as u128);
+                for item in v {
+                    self.value(item);
+                }
+            },
+            AstAny::Unsport => self.uint(19),
+            // handler values have no byte form, they come back unread
+            AstAny::Unknow | AstAny::Custom(_) => self.uint(20),
+        }
+    }
+
+    /// the tokens and everything under them, walked with an explicit stack so no tree is too deep
+    fn tokens(&mut self, tokens: &[LexToken]) {
+        self.uint(tokens.len() as u128);
+        let mut open = vec![tokens.iter()];
+        while let Some(level) = open.last_mut() {
+            let Some(token) = level.next() else {
+                open.pop();
+                continue;
+            };
+            self.token(token);
+            self.uint(token.subs.len() as u128);
+            open.push(token.subs.iter());
+        }
+    }
+
+    fn token(&mut self, token: &LexToken) {
+        self.bytes(token.type_name().as_bytes());
+        self.uint(token.lineno as u128);
+        self.uint(token.start as u128);
+        self.uint(token.end as u128);
+        self.uint(token.rule.map_or(0, |r| r as u128 + 1));
+        self.uint(token.synthetic as u128 | (token.closed_implicitly as u128) << 1);
+        self.uint(token.flags as u128);
+        self.uint(token.captures.len() as u128);
+        for (start, end) in &token.captures {
+            self.uint(*start as u128);
+            self.uint(*end as u128);
+        }
+        self.value(&token.value);
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self) -> AstError {
+        AstError::new_invalid_bytes_error(self.pos)
+    }
+
+    fn uint(&mut self) -> AstResult<u128> {
+        let mut v = 0u128;
+        let mut shift = 0;
+        loop {
+            let byte = *self.data.get(self.pos).ok_or_else(|| self.error())?;
+            if shift >= 128 {
+                return Err(self.error());
+            }
+            self.pos += 1;
+            v |= ((byte & 0x7f) as u128) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(v);
+            }
+            shift += 7;
+        }
+    }
+
+    fn int(&mut self) -> AstResult<i128> {
+        let v = self.uint()?;
+        Ok((v >> 1) as i128 ^ -((v & 1) as i128))
+    }
+
+    fn num<T: TryFrom<u128>>(&mut self) -> AstResult<T> {
+        let v = self.uint()?;
+        T::try_from(v).map_err(|_| self.error())
+    }
+
+    fn signed<T: TryFrom<i128>>(&mut self) -> AstResult<T> {
+        let v = self.int()?;
+        T::try_from(v).map_err(|_| self.error())
+    }
+
+    fn bytes(&mut self) -> AstResult<&'a [u8]> {
+        let len: usize = self.num()?;
+        let end = self.pos.checked_add(len).filter(|end| *end <= self.data.len()).ok_or_else(|| self.error())?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn str(&mut self) -> AstResult<&'a str> {
+        let start = self.pos;
+        let bytes = self.bytes()?;
+        std::str::from_utf8(bytes).map_err(|_| AstError::new_invalid_bytes_error(start))
+    }
+
+    fn value(&mut self, depth: usize) -> AstResult<AstAny> {
+        Ok(match self.uint()? {
+            0 => AstAny::Bool(self.uint()? != 0),
+            1 => AstAny::U8(self.num()?),
+            2 => AstAny::I8(self.signed()?),
+            3 => AstAny::U16(self.num()?),
+            4 => AstAny::I16(self.signed()?),
+            5 => AstAny::U32(self.num()?),
+            6 => AstAny::I32(self.signed()?),
+            7 => AstAny::U64(self.num()?),
+            8 => AstAny::I64(self.signed()?),
+            9 => AstAny::U128(self.uint()?),
+            10 => AstAny::I128(self.int()?),
+            11 => AstAny::Isize(self.signed()?),
+            12 => AstAny::Usize(self.num()?),
+            13 => AstAny::F32(f32::from_bits(self.num()?)),
+            14 => AstAny::F64(f64::from_bits(self.num()?)),
+            15 => AstAny::Str(self.str()?.to_string()),
+            16 => AstAny::Char(char::from_u32(self.num()?).ok_or_else(|| self.error())?),
+            17 => AstAny::Other(self.bytes()?.to_vec()),
+            18 if depth < MAX_VALUE_DEPTH => {
+                let len: usize = self.num()?;
+                let mut items = Vec::with_capacity(len.min(self.data.len()));
+                for _ in 0..len {
+                    items.push(self.value(depth + 1)?);
+                }
+                AstAny::List(items)
+            }
+            19 => AstAny::Unsport,
+            20 => AstAny::Unknow,
+            _ => return Err(self.error()),
+        })
+    }
+
+    /// the tokens `Writer::tokens` wrote, read back with an explicit stack
+    fn tokens(&mut self, data: &Arc<String>, types: &HashSet<&'static str>, names: &TypeInterner) -> AstResult<Vec<LexToken>> {
+        let len: usize = self.num()?;
+        let mut tokens = Vec::with_capacity(len.min(self.data.len()));
+        let mut left = len;
+        // the tokens whose subs are still being read, with how many are to come
+        let mut open: Vec<(LexToken, usize)> = vec![];
+        loop {
+            match open.last() {
+                Some((_, 0)) => {
+                    let (token, _) = open.pop().unwrap();
+                    match open.last_mut() {
+                        Some((parent, subs)) => {
+                            parent.subs.push(token);
+                            *subs -= 1;
+                        }
+                        None => {
+                            tokens.push(token);
+                            left -= 1;
+                        }
+                    }
+                }
+                None if left == 0 => return Ok(tokens),
+                _ => {
+                    let token = self.token(data, types, names)?;
+                    let subs: usize = self.num()?;
+                    open.push((token, subs));
+                }
+            }
+        }
+    }
+
+    fn token(&mut self, data: &Arc<String>, types: &HashSet<&'static str>, names: &TypeInterner) -> AstResult<LexToken> {
+        let at = self.pos;
+        let ty = self.str()?;
+        let (ty, name) = match (types.get(ty), names.get(ty)) {
+            (Some(ty), _) => (*ty, None),
+            (None, Some(name)) => ("named", Some(name)),
+            (None, None) => return Err(AstError::new_invalid_bytes_error(at)),
+        };
+        let lineno = self.num()?;
+        let start: usize = self.num()?;
+        let end: usize = self.num()?;
+        if start > end || end > data.len() || !data.is_char_boundary(start) || !data.is_char_boundary(end) {
+            return Err(self.error());
+        }
+        let rule = match self.num::<usize>()? {
+            0 => None,
+            r => Some(r - 1),
+        };
+        let made = self.uint()?;
+        let flags = self.num()?;
+        let len: usize = self.num()?;
+        let mut captures = Vec::with_capacity(len.min(self.data.len()));
+        for _ in 0..len {
+            captures.push((self.num()?, self.num()?));
+        }
+        let value = self.value(0)?;
+        Ok(LexToken { ty, data: data.clone(), lineno, start, end, subs: vec![], value, rule, synthetic: made & 1 != 0, closed_implicitly: made & 2 != 0, canonical: None, captures, flags, name })
+    }
+}
+
+impl LexToken {
+    /// Encode a token tree compactly, the tokens are taken to share one source which is stored once.
+    pub fn to_bytes(tokens: &[LexToken]) -> Vec<u8> {
+        let mut writer = Writer(MAGIC.to_vec());
+        writer.bytes(tokens.first().map_or(&b""[..], |t| t.data.as_bytes()));
+        writer.tokens(tokens);
+        writer.0
+    }
+
+    /// Decode `to_bytes` output, every token points into one rebuilt source. A token type not
+    /// in `types`, usually `Lexer::known_types`, fails like any other bad byte.
+    pub fn from_bytes(bytes: &[u8], types: &HashSet<&'static str>) -> AstResult<Vec<LexToken>> {
+        LexToken::from_bytes_named(bytes, types, &TypeInterner::default())
+    }
+
+    /// `from_bytes` also taking the runtime types of `names`, usually `Lexer::types`
+    pub fn from_bytes_named(bytes: &[u8], types: &HashSet<&'static str>, names: &TypeInterner) -> AstResult<Vec<LexToken>> {
+        let Some(rest) = bytes.strip_prefix(MAGIC) else {
+            return Err(AstError::new_invalid_bytes_error(0));
+        };
+        let mut reader = Reader { data: rest, pos: 0 };
+        let data = Arc::new(reader.str()?.to_string());
+        let tokens = reader.tokens(&data, types, names)?;
+        if reader.pos != rest.len() {
+            return Err(reader.error());
+        }
+        Ok(tokens)
+    }
+}
+
+impl<H: Handler> Lexer<H> {
+    /// every token type this lexer can hand out, for `LexToken::from_bytes`
+    pub fn known_types(&self) -> HashSet<&'static str> {
+        let mut types: HashSet<&'static str> = ["lit", "newline", "comment", "trivia", "ws", "text", "interp", "directive", "raw"].into();
+        types.extend(self.res.iter().map(|re| re.ty));
+        types.extend(self.strings.iter().map(|s| s.ty));
+        types.extend(self.heredocs.iter().map(|(_, ty)| *ty));
+        types.extend(self.keywords.values().copied());
+        types.extend(self.precs.iter().map(|prec| prec.ty));
+        types.extend(self.extra_types.iter().copied());
+        types
+    }
+}
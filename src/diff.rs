@@ -0,0 +1,68 @@
+use std::collections::HashMap;
+
+use crate::{LexToken, TypeName};
+
+/// One step turning an old token list into a new one, indexes are into the two lists.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TokenEdit {
+    Insert { new: usize },
+    Delete { old: usize },
+    Replace { old: usize, new: usize },
+}
+
+/// The edits from `old` to `new` in order, tokens being the same when their type and text
+/// agree wherever they sit. A longest common subsequence keeps the rest, and a run of
+/// deletes next to a run of inserts is paired up into replaces.
+pub fn diff_tokens(old: &[LexToken], new: &[LexToken]) -> Vec<TokenEdit> {
+    let same = |a: &LexToken, b: &LexToken| a.type_name() == b.type_name() && a.get_value() == b.get_value();
+    let (n, m) = (old.len(), new.len());
+    let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if same(&old[i], &new[j]) { lcs[i + 1][j + 1] + 1 } else { lcs[i + 1][j].max(lcs[i][j + 1]) };
+        }
+    }
+
+    let mut edits = vec![];
+    let (mut deleted, mut inserted) = (vec![], vec![]);
+    let flush = |edits: &mut Vec<TokenEdit>, deleted: &mut Vec<usize>, inserted: &mut Vec<usize>| {
+        let pairs = deleted.len().min(inserted.len());
+        edits.extend(deleted.iter().zip(inserted.iter()).map(|(&old, &new)| TokenEdit::Replace { old, new }));
+        edits.extend(deleted[pairs..].iter().map(|&old| TokenEdit::Delete { old }));
+        edits.extend(inserted[pairs..].iter().map(|&new| TokenEdit::Insert { new }));
+        deleted.clear();
+        inserted.clear();
+    };
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && same(&old[i], &new[j]) {
+            flush(&mut edits, &mut deleted, &mut inserted);
+            i += 1;
+            j += 1;
+        } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+            inserted.push(j);
+            j += 1;
+        } else {
+            deleted.push(i);
+            i += 1;
+        }
+    }
+    flush(&mut edits, &mut deleted, &mut inserted);
+    edits
+}
+
+/// how many tokens of each `type_name` `tokens` hold, those in `subs` included
+pub fn type_histogram(tokens: &[LexToken]) -> HashMap<TypeName, usize> {
+    let mut counts: HashMap<TypeName, usize> = HashMap::new();
+    let mut stack: Vec<&LexToken> = tokens.iter().collect();
+    while let Some(token) = stack.pop() {
+        match counts.get_mut(token.type_name()) {
+            Some(count) => *count += 1,
+            None => {
+                counts.insert(token.name.clone().unwrap_or_else(|| token.ty.into()), 1);
+            }
+        }
+        stack.extend(token.subs.iter());
+    }
+    counts
+}
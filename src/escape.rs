@@ -0,0 +1,41 @@
+use crate::{AstResult, AstError};
+
+/// Turns the raw text between a string's quotes into its value.
+pub trait EscapeDecoder {
+    /// `start` is the byte offset of `text` in the source, used for error positions
+    fn decode(&self, text: &str, start: usize) -> AstResult<String>;
+}
+
+/// Decodes `\n`, `\t`, `\r`, `\0`, `\\`, `\"`, `\'`, `\$` and `\uXXXX`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultEscapeDecoder;
+
+impl EscapeDecoder for DefaultEscapeDecoder {
+    fn decode(&self, text: &str, start: usize) -> AstResult<String> {
+        let mut value = String::with_capacity(text.len());
+        let mut chars = text.char_indices();
+        while let Some((idx, c)) = chars.next() {
+            if c != '\\' {
+                value.push(c);
+                continue;
+            }
+            let invalid = || AstError::new_invalid_escape_error(start + idx);
+            let c = match chars.next().ok_or_else(invalid)?.1 {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                '0' => '\0',
+                c @ ('\\' | '"' | '\'' | '$') => c,
+                'u' => {
+                    let hex = text.get(idx + 2..idx + 6).filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit())).ok_or_else(invalid)?;
+                    let code = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+                    chars.nth(3);
+                    char::from_u32(code).ok_or_else(invalid)?
+                }
+                _ => return Err(invalid()),
+            };
+            value.push(c);
+        }
+        Ok(value)
+    }
+}
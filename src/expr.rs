@@ -0,0 +1,93 @@
+use crate::{Handler, Lexer, LexToken, AstResult, AstError};
+
+/// A typed expression tree from `parse_expr_typed`, each span is the `(start, end)` byte range
+/// in `data` from the first to the last token of the expression, closing brackets are not kept.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    /// a leaf `Handler::is_const` accepts
+    Num { text: String, span: (usize, usize) },
+    /// any other leaf
+    Ident { name: String, span: (usize, usize) },
+    Binary { op: String, lhs: Box<Expr>, rhs: Box<Expr>, span: (usize, usize) },
+    /// a prefix operator, or a postfix one when `postfix` is set
+    Unary { op: String, operand: Box<Expr>, postfix: bool, span: (usize, usize) },
+    Ternary { cond: Box<Expr>, then: Box<Expr>, other: Box<Expr>, span: (usize, usize) },
+    /// an expression written in brackets, its span is that of the expression inside
+    Group(Box<Expr>),
+    Call { callee: Box<Expr>, args: Vec<Expr>, span: (usize, usize) },
+    /// the elements of `list_group`
+    List { items: Vec<Expr>, span: (usize, usize) },
+    /// a construct from `add_mixfix` with its expressions in order
+    Mixfix { name: &'static str, parts: Vec<Expr>, span: (usize, usize) },
+}
+
+impl Expr {
+    pub fn span(&self) -> (usize, usize) {
+        match self {
+            Expr::Group(inner) => inner.span(),
+            Expr::Num { span, .. } | Expr::Ident { span, .. } | Expr::Binary { span, .. }
+            | Expr::Unary { span, .. } | Expr::Ternary { span, .. } | Expr::Call { span, .. }
+            | Expr::List { span, .. } | Expr::Mixfix { span, .. } => *span,
+        }
+    }
+}
+
+impl<H: Handler> Lexer<H> {
+    /// Lex and parse the rest of the input into an `Expr`.
+    pub fn parse_expr_typed(&mut self) -> AstResult<Expr> {
+        self.parser_token()?;
+        let tree = self.parse_expr(&self.tokenstack)?;
+        self.to_expr(&tree)
+    }
+
+    /// an expression tree from `parse_expr` as an `Expr`
+    pub fn to_expr(&self, node: &LexToken) -> AstResult<Expr> {
+        // the nodes being converted with the `Expr`s of their subs so far, walked without
+        // recursing so long chains convert like short ones
+        let mut stack: Vec<(&LexToken, Vec<Expr>)> = vec![(node, vec![])];
+        loop {
+            let (node, done) = stack.last().unwrap();
+            if let Some(next) = node.subs.get(done.len()) {
+                stack.push((next, Vec::with_capacity(next.subs.len())));
+                continue;
+            }
+            let (node, subs) = stack.pop().unwrap();
+            let mut expr = self.node_expr(node, subs)?;
+            if node.has_flag(LexToken::FLAG_GROUPED) {
+                expr = Expr::Group(Box::new(expr));
+            }
+            match stack.last_mut() {
+                Some((_, done)) => done.push(expr),
+                None => return Ok(expr),
+            }
+        }
+    }
+
+    /// `node` as an `Expr` given those of its subs, its span widened by theirs
+    fn node_expr(&self, node: &LexToken, subs: Vec<Expr>) -> AstResult<Expr> {
+        let span = subs.iter().map(Expr::span).fold((node.start, node.end), |(s, e), (ss, se)| (s.min(ss), e.max(se)));
+        let key = (node.ty, node.get_value());
+        let mixfix = self.mixfix.iter()
+            .find(|m| m.markers.first() == Some(&key.1) && m.markers.len() == node.subs.len());
+        if let Some(mixfix) = mixfix {
+            return Ok(Expr::Mixfix { name: mixfix.name, parts: subs, span });
+        }
+        if key == self.list_group {
+            return Ok(Expr::List { items: subs, span });
+        }
+        let mut parts = subs.into_iter();
+        let mut sub = || Box::new(parts.next().unwrap());
+        if key == self.call_group && !node.subs.is_empty() {
+            return Ok(Expr::Call { callee: sub(), args: parts.collect(), span });
+        }
+        let op = node.op_text().to_string();
+        Ok(match node.subs.len() {
+            0 if self.handler.is_const(node) => Expr::Num { text: key.1.to_string(), span },
+            0 => Expr::Ident { name: key.1.to_string(), span },
+            1 => Expr::Unary { op, operand: sub(), postfix: node.start >= node.subs[0].end, span },
+            2 => Expr::Binary { op, lhs: sub(), rhs: sub(), span },
+            3 => Expr::Ternary { cond: sub(), then: sub(), other: sub(), span },
+            _ => return Err(AstError::new_unexpected_token_error(node.clone_base_token())),
+        })
+    }
+}
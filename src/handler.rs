@@ -1,10 +1,106 @@
-use crate::{AstAny, lexer::LexToken, AstResult};
+use std::collections::HashMap;
+
+use crate::{AstAny, lexer::LexToken, AstResult, AstError};
+
+/// Read-only view of the lexer given to `Handler::on_read_ctx`.
+pub struct LexerCtx<'a> {
+    pub(crate) data: &'a str,
+    pub(crate) prec_hash: &'a HashMap<(&'static str, &'static str), (bool, i32)>,
+}
+
+impl<'a> LexerCtx<'a> {
+    pub fn source(&self) -> &'a str {
+        self.data
+    }
+
+    /// `(left, level)` of the operator used infix
+    pub fn precedence(&self, ty: &'static str, op: &str) -> Option<(bool, i32)> {
+        self.prec_hash.get(&(ty, op)).copied()
+    }
+
+    /// 1-based line and char column of the byte offset `pos`
+    pub fn line_col(&self, pos: usize) -> (usize, usize) {
+        let before = &self.data[..pos];
+        let line_start = before.rfind('\n').map_or(0, |p| p + 1);
+        (before.matches('\n').count() + 1, before[line_start..].chars().count() + 1)
+    }
+}
 
 pub trait Handler {
     #[inline]
     fn on_read(&mut self, _token: &mut LexToken) -> AstResult<AstAny> {
         Ok(AstAny::Unsport)
     }
+
+    /// a char no rule matches at `pos`, `Ok(None)` skips it and a returned token is emitted,
+    /// the lexer fills in its `data` and `lineno`
+    #[inline]
+    fn on_unknown(&mut self, ch: char, pos: usize) -> AstResult<Option<LexToken>> {
+        Err(AstError::new_unknown_char_error(ch, pos))
+    }
+
+    /// every token `parser_token` lexed, before grouping, `Ok(None)` drops it
+    #[inline]
+    fn on_token(&mut self, token: LexToken) -> AstResult<Option<LexToken>> {
+        Ok(Some(token))
+    }
+
+    /// `on_read` with access to the lexer, the evaluator calls this one
+    #[inline]
+    fn on_read_ctx(&mut self, token: &mut LexToken, _ctx: &LexerCtx) -> AstResult<AstAny> {
+        self.on_read(token)
+    }
+
+    /// whether a leaf is a literal `fold_constants` may evaluate ahead of time
+    #[inline]
+    fn is_const(&self, token: &LexToken) -> bool {
+        token.ty == "num"
+    }
+
+    #[inline]
+    fn on_unary(&mut self, _op: &LexToken, _value: AstAny) -> AstResult<AstAny> {
+        Ok(AstAny::Unsport)
+    }
+
+    #[inline]
+    fn on_binary(&mut self, _op: &LexToken, _lhs: AstAny, _rhs: AstAny) -> AstResult<AstAny> {
+        Ok(AstAny::Unsport)
+    }
+
+    /// pick the branch of `cond ? a : b`, only the chosen one is evaluated
+    #[inline]
+    fn on_ternary(&mut self, _op: &LexToken, cond: &AstAny) -> AstResult<bool> {
+        Ok(cond.is_truthy())
+    }
+
+    /// an operator standing after its operand, like `a!`
+    #[inline]
+    fn on_postfix(&mut self, _op: &LexToken, _value: AstAny) -> AstResult<AstAny> {
+        Ok(AstAny::Unsport)
+    }
+
+    /// store `name = value`, the name is not evaluated
+    #[inline]
+    fn on_assign(&mut self, _name: &LexToken, _value: AstAny) -> AstResult<AstAny> {
+        Ok(AstAny::Unsport)
+    }
+
+    /// a construct from `add_mixfix` with the values of its expressions in order
+    #[inline]
+    fn on_mixfix(&mut self, _name: &'static str, _parts: Vec<AstAny>) -> AstResult<AstAny> {
+        Ok(AstAny::Unsport)
+    }
+
+    /// `callee(args)`, the callee is not evaluated
+    #[inline]
+    fn on_call(&mut self, _callee: &LexToken, _args: Vec<AstAny>) -> AstResult<AstAny> {
+        Ok(AstAny::Unsport)
+    }
+
+    #[inline]
+    fn on_list(&mut self, _token: &LexToken, items: Vec<AstAny>) -> AstResult<AstAny> {
+        Ok(AstAny::List(items))
+    }
 }
 
 pub struct DefaultHandler;
@@ -0,0 +1,36 @@
+use std::collections::HashSet;
+use std::sync::Arc;
+
+/// A token type name made at runtime, shared by every token of its rule.
+pub type TypeName = Arc<str>;
+
+/// The type names a lexer made at runtime, each distinct name is kept once and the same
+/// `Arc<str>` handed out after. Names live as long as the lexer and tokens holding them.
+#[derive(Clone, Debug, Default)]
+pub struct TypeInterner {
+    names: HashSet<TypeName>,
+}
+
+impl TypeInterner {
+    pub fn intern(&mut self, name: &str) -> TypeName {
+        if let Some(ty) = self.names.get(name) {
+            return ty.clone();
+        }
+        let ty: TypeName = Arc::from(name);
+        self.names.insert(ty.clone());
+        ty
+    }
+
+    /// the interned name, `None` when `name` was never interned
+    pub fn get(&self, name: &str) -> Option<TypeName> {
+        self.names.get(name).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+}
@@ -1,10 +1,9 @@
-use std::{ops::BitAnd, sync::Arc, collections::HashMap, vec};
+use std::{borrow::Cow, ops::BitAnd, sync::{Arc, Mutex}, collections::{HashMap, HashSet}, vec};
 use regex::Regex;
 use std::fmt::Debug;
 
-use crate::{Handler, AstAny, AstResult, AstError};
+use crate::{Handler, DefaultHandler, LexerCtx, AstAny, AstResult, AstError, Preprocessor, SourceMap, EscapeDecoder, TypeInterner, TypeName};
 
-#[derive(Clone)]
 pub struct LexToken {
     pub ty: &'static str,
     pub data: Arc<String>,
@@ -13,25 +12,343 @@ pub struct LexToken {
     pub end: usize,
     pub subs: Vec<LexToken>,
     pub value: AstAny,
+    /// index in `res` of the regex rule that produced the token
+    pub rule: Option<usize>,
+    /// made up by the lexer rather than read from `data`, like a closer inserted by `recover_brackets`
+    pub synthetic: bool,
+    /// a group opener whose closer `recover_brackets` made up
+    pub closed_implicitly: bool,
+    /// the operator an alias stands for, set on parsed operator nodes by `add_operator_alias`
+    pub canonical: Option<&'static str>,
+    /// byte spans of the rule's capture groups when it opted in, a group that took no part
+    /// is empty at `start`
+    pub captures: Vec<(usize, usize)>,
+    /// bits passes set to tag tokens, see the `FLAG_` constants, never compared or hashed
+    pub flags: u32,
+    /// the runtime type of a rule from `add_regex_named`, `ty` is then `"named"`
+    pub name: Option<TypeName>,
+}
+
+/// Tokens compare by type, position, text, `subs` and `value`, not by the rule that made them.
+/// Pairs left to compare go on a stack, so long chains compare without recursing.
+impl PartialEq for LexToken {
+    fn eq(&self, other: &Self) -> bool {
+        let mut stack = vec![(self, other)];
+        while let Some((a, b)) = stack.pop() {
+            if a.type_name() != b.type_name()
+                || a.start != b.start
+                || a.end != b.end
+                || a.lineno != b.lineno
+                || a.get_value() != b.get_value()
+                || a.subs.len() != b.subs.len()
+                || a.value != b.value
+            {
+                return false;
+            }
+            stack.extend(a.subs.iter().zip(&b.subs));
+        }
+        true
+    }
+}
+
+/// Copies are built bottom up from a stack, like `Drop` a long chain must not recurse.
+impl Clone for LexToken {
+    fn clone(&self) -> Self {
+        let copy = |t: &LexToken| {
+            let mut base = t.clone_base_token();
+            base.value = t.value.clone();
+            base
+        };
+        // (token, its copy, next sub to visit)
+        let mut stack = vec![(self, copy(self), 0)];
+        loop {
+            let (token, _, next) = stack.last_mut().unwrap();
+            if let Some(sub) = token.subs.get(*next) {
+                *next += 1;
+                stack.push((sub, copy(sub), 0));
+                continue;
+            }
+            let (_, done, _) = stack.pop().unwrap();
+            match stack.last_mut() {
+                Some((_, parent, _)) => parent.subs.push(done),
+                None => return done,
+            }
+        }
+    }
+}
+
+/// Trees are taken apart with a stack of their own, a long chain would overflow dropping
+/// each level inside the one above.
+impl Drop for LexToken {
+    fn drop(&mut self) {
+        if self.subs.iter().all(|t| t.subs.is_empty()) {
+            return;
+        }
+        let mut stack = std::mem::take(&mut self.subs);
+        while let Some(mut token) = stack.pop() {
+            stack.append(&mut token.subs);
+        }
+    }
 }
 
 impl Debug for LexToken {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let val = self.data.get(self.start..self.end).unwrap();
-        f.debug_struct("LexToken").field("ty", &self.ty).field("value", &val).field("lineno", &self.lineno).field("start", &self.start).field("end", &self.end).field("subs", &self.subs).field("value", &self.value).finish()
+        f.debug_struct("LexToken").field("ty", &self.type_name()).field("value", &val).field("lineno", &self.lineno).field("start", &self.start).field("end", &self.end).field("subs", &self.subs).field("value", &self.value).finish()
     }
 }
 
 impl LexToken {
+    pub const FLAG_VISITED: u32 = 1;
+    pub const FLAG_ERROR: u32 = 1 << 1;
+    pub const FLAG_FOLDED: u32 = 1 << 2;
+    /// set by the parser on an expression that stood in brackets
+    pub const FLAG_GROUPED: u32 = 1 << 3;
+    /// set by `to_rpn` on an operator standing prefix, it takes one operand off the stack
+    pub const FLAG_PREFIX: u32 = 1 << 4;
+    /// the first bit left free for the caller's own flags
+    pub const FLAG_USER: u32 = 1 << 8;
+
     pub fn get_value(&self) -> &str {
         self.data.get(self.start..self.end).unwrap()
     }
 
+    /// the raw bytes of the span, unlike `get_value` it does not need char boundaries
+    pub fn bytes(&self) -> &[u8] {
+        &self.data.as_bytes()[self.start..self.end]
+    }
+
     pub fn clone_base_token(&self) -> LexToken {
-        LexToken { ty: self.ty, data: self.data.clone(), lineno: self.lineno, start: self.start, end: self.end, subs: vec![], value: AstAny::Unknow }
+        LexToken { ty: self.ty, data: self.data.clone(), lineno: self.lineno, start: self.start, end: self.end, subs: vec![], value: AstAny::Unknow, rule: self.rule, synthetic: self.synthetic, closed_implicitly: self.closed_implicitly, canonical: self.canonical, captures: self.captures.clone(), flags: self.flags, name: self.name.clone() }
+    }
+
+    /// `ty`, or the runtime `name` for a token of a rule from `add_regex_named`
+    pub fn type_name(&self) -> &str {
+        self.name.as_deref().unwrap_or(self.ty)
+    }
+
+    /// FNV-1a over `type_name`, text and the `subs` count of every token in pre-order.
+    /// Positions, `lineno` and `value` are left out, so the same tree anywhere in any
+    /// layout hashes alike, and the result does not change between runs or builds.
+    pub fn fingerprint(&self) -> u64 {
+        fn feed(hash: &mut u64, bytes: &[u8]) {
+            for b in bytes {
+                *hash ^= *b as u64;
+                *hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        let mut hash = 0xcbf29ce484222325;
+        let mut stack = vec![self];
+        while let Some(token) = stack.pop() {
+            for part in [token.type_name().as_bytes(), token.get_value().as_bytes()] {
+                feed(&mut hash, &(part.len() as u64).to_le_bytes());
+                feed(&mut hash, part);
+            }
+            feed(&mut hash, &(token.subs.len() as u64).to_le_bytes());
+            stack.extend(token.subs.iter().rev());
+        }
+        hash
+    }
+
+    /// The text of `tokens` and everything under them in source order, synthetic tokens left out.
+    /// With `Lexer::trivia` on and nothing dropped this is the source the tokens came from.
+    pub fn to_source(tokens: &[LexToken]) -> String {
+        let mut out = String::new();
+        let mut stack: Vec<&LexToken> = tokens.iter().rev().collect();
+        while let Some(token) = stack.pop() {
+            if !token.synthetic {
+                out.push_str(token.get_value());
+            }
+            stack.extend(token.subs.iter().rev());
+        }
+        out
+    }
+
+    /// The source range the token and everything under it cover, for a group from the opener
+    /// through the closer and for a parsed operator from its first operand through its last.
+    pub fn full_span(&self) -> (usize, usize) {
+        let mut span = (self.start, self.end);
+        let mut stack: Vec<&LexToken> = self.subs.iter().collect();
+        while let Some(token) = stack.pop() {
+            span = (span.0.min(token.start), span.1.max(token.end));
+            stack.extend(&token.subs);
+        }
+        span
+    }
+
+    /// whether the token has type `ty` and text `text`, wherever it sits
+    pub fn same_token(&self, ty: &str, text: &str) -> bool {
+        self.type_name() == ty && self.get_value() == text
+    }
+
+    /// Move the token and everything under it onto `new_data`, shifting each span by
+    /// `offset_delta` and recounting `lineno`. Nothing changes when a span would not fit.
+    pub fn rebase(&mut self, new_data: Arc<String>, offset_delta: isize) -> AstResult<()> {
+        let shift = |pos: usize| pos.checked_add_signed(offset_delta).filter(|p| new_data.is_char_boundary(*p));
+        let mut stack = vec![&*self];
+        while let Some(token) = stack.pop() {
+            let spans = std::iter::once((token.start, token.end)).chain(token.captures.iter().copied());
+            for (start, end) in spans {
+                if shift(start).is_none() || shift(end).is_none() {
+                    return Err(AstError::new_out_of_range_error(start, end));
+                }
+            }
+            stack.extend(token.subs.iter());
+        }
+        let mut stack = vec![self];
+        while let Some(token) = stack.pop() {
+            token.start = shift(token.start).unwrap();
+            token.end = shift(token.end).unwrap();
+            for (start, end) in token.captures.iter_mut() {
+                (*start, *end) = (shift(*start).unwrap(), shift(*end).unwrap());
+            }
+            token.lineno = new_data[..token.start].matches('\n').count() + 1;
+            token.data = new_data.clone();
+            stack.extend(token.subs.iter_mut());
+        }
+        Ok(())
+    }
+
+    /// `self` and `other` as one `ty` token, `other` must start where `self` ends in the same source
+    pub fn join(self, other: LexToken, ty: &'static str) -> AstResult<LexToken> {
+        if !Arc::ptr_eq(&self.data, &other.data) && self.data != other.data {
+            return Err(AstError::new_unexpected_token_error(other.clone_base_token()));
+        }
+        if self.end != other.start {
+            return Err(AstError::new_out_of_range_error(self.end, other.start));
+        }
+        let mut token = self.clone_base_token();
+        token.ty = ty;
+        token.end = other.end;
+        token.rule = None;
+        token.captures.clear();
+        Ok(token)
+    }
+
+    /// the token retyped, for passes that reclassify after lexing
+    pub fn with_type(mut self, ty: &'static str) -> LexToken {
+        self.set_type(ty);
+        self
+    }
+
+    /// retype in place, a retyped token no longer equals its old self
+    pub fn set_type(&mut self, ty: &'static str) {
+        self.ty = ty;
+    }
+
+    /// set or clear the bits of `flag`
+    pub fn set_flag(&mut self, flag: u32, on: bool) {
+        if on {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
+        }
+    }
+
+    /// whether every bit of `flag` is set
+    pub fn has_flag(&self, flag: u32) -> bool {
+        self.flags & flag == flag
+    }
+
+    pub fn rule_id(&self) -> Option<usize> {
+        self.rule
+    }
+
+    /// the canonical operator for an alias, the text otherwise
+    pub fn op_text(&self) -> &str {
+        self.canonical.unwrap_or_else(|| self.get_value())
+    }
+
+    /// `self` and every token under it whose `type_name` is `ty`, in source order
+    pub fn collect_type(&self, ty: &'static str) -> Vec<&LexToken> {
+        let mut found = vec![];
+        let mut stack = vec![self];
+        while let Some(token) = stack.pop() {
+            if token.type_name() == ty {
+                found.push(token);
+            }
+            stack.extend(token.subs.iter().rev());
+        }
+        // parsed operator nodes sit before their left operand
+        found.sort_by_key(|t| t.start);
+        found
+    }
+
+    /// render a parsed tree as `(op children...)`, leaves as their text
+    pub fn to_sexpr(&self) -> String {
+        // what is left to write, in reverse, so long chains need no recursion
+        let mut out = String::new();
+        let mut stack = vec![Ok(self)];
+        while let Some(part) = stack.pop() {
+            let token = match part {
+                Ok(token) => token,
+                Err(text) => {
+                    out.push_str(text);
+                    continue;
+                }
+            };
+            if token.subs.is_empty() {
+                out.push_str(token.get_value());
+                continue;
+            }
+            out.push('(');
+            out.push_str(token.get_value());
+            stack.push(Err(")"));
+            for sub in token.subs.iter().rev() {
+                stack.extend([Ok(sub), Err(" ")]);
+            }
+        }
+        out
+    }
+
+    /// zero based line and UTF-16 column of the start, as the Language Server Protocol counts
+    pub fn lsp_position<H: Handler>(&self, lexer: &Lexer<H>) -> (u32, u32) {
+        let before = &self.data[..self.start];
+        let line_start = before.rfind('\n').map_or(0, |p| p + 1);
+        let line = before.matches('\n').count();
+        let col = lexer.utf16_offset(self.start) - lexer.utf16_offset(line_start);
+        (line as u32, col as u32)
+    }
+
+    pub fn is_operator<H: Handler>(&self, lexer: &Lexer<H>) -> bool {
+        lexer.prec_hash.contains_key(&(self.ty, self.get_value()))
+    }
+
+    pub fn is_open_bracket<H: Handler>(&self, lexer: &Lexer<H>) -> bool {
+        lexer.hash_matchs.contains_key(&(self.ty, self.get_value()))
+    }
+
+    pub fn is_close_bracket<H: Handler>(&self, lexer: &Lexer<H>) -> bool {
+        lexer.hash_matchs.iter().any(|((ty, _), ends)| *ty == self.ty && ends.contains(&self.get_value()))
+    }
+
+    /// Check a grouped tree as `group` builds it: each opener ends its `subs` with a closer
+    /// `hash_matchs` accepts for it, or a synthetic one, and no closer stands anywhere else.
+    pub fn validate_structure<H: Handler>(&self, lexer: &Lexer<H>) -> AstResult<()> {
+        let mut stack = vec![(self, false)];
+        while let Some((token, closes)) = stack.pop() {
+            if token.is_open_bracket(lexer) {
+                match token.subs.last() {
+                    Some(last) if last.synthetic || lexer.is_closer_of(token, last) => {}
+                    _ => return Err(AstError::new_no_match_close_error(token.clone_base_token())),
+                }
+            } else if token.is_close_bracket(lexer) && !closes {
+                return Err(AstError::new_unexpected_token_error(token.clone_base_token()));
+            }
+            let opener = token.is_open_bracket(lexer);
+            let last = token.subs.len().saturating_sub(1);
+            stack.extend(token.subs.iter().enumerate().map(|(idx, sub)| (sub, opener && idx == last)));
+        }
+        Ok(())
     }
 }
 
+/// Panic unless `tokens` are exactly `shape` as `(ty, text)` pairs, positions are not compared.
+pub fn assert_token_shape(tokens: &[LexToken], shape: &[(&str, &str)]) {
+    let found: Vec<(&str, &str)> = tokens.iter().map(|t| (t.type_name(), t.get_value())).collect();
+    assert!(found.as_slice() == shape, "token shape mismatch\n  found: {:?}\n   want: {:?}", found, shape);
+}
+
 #[derive(Clone, Debug)]
 pub struct LexPrec {
     pub ty: &'static str,
@@ -47,17 +364,20 @@ impl LexPrec {
     }
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub struct LexGroupToken {
     pub tokens: Vec<LexGroupToken>,
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Debug)]
 pub enum GroupOrToken {
     Group(Vec<LexToken>),
     Token(LexToken),
 }
 
+#[allow(dead_code)]
 impl GroupOrToken {
     pub fn group() -> GroupOrToken {
         GroupOrToken::Group(vec![])
@@ -72,23 +392,462 @@ impl GroupOrToken {
 pub struct LexRegex {
     pub re: Regex,
     pub ty: &'static str,
+    /// rules are tried highest first, equal ones in registration order
+    pub priority: i32,
+    /// keep the capture group spans on the token
+    pub capturing: bool,
+    /// the runtime type its tokens carry, see `Lexer::add_regex_named`
+    pub name: Option<TypeName>,
+}
+
+impl LexRegex {
+    pub fn new(ty: &'static str, re: Regex) -> Self {
+        LexRegex {
+            ty, re, priority: 0, capturing: false, name: None
+        }
+    }
+
+    pub fn with_priority(ty: &'static str, re: Regex, priority: i32) -> Self {
+        LexRegex {
+            ty, re, priority, capturing: false, name: None
+        }
+    }
+}
+
+/// Rewrites each raw token into zero or more tokens before grouping.
+#[derive(Clone)]
+pub struct LexTransformer(Arc<Mutex<dyn FnMut(LexToken) -> Vec<LexToken> + Send>>);
+
+impl Debug for LexTransformer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LexTransformer")
+    }
+}
+
+/// How `get_token` picks between regex rules matching at the same position.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum MatchPolicy {
+    /// the first rule in registration order
+    #[default]
+    FirstMatch,
+    /// the rule with the longest match
+    LongestMatch,
+}
+
+/// What the parser makes of the `:` in `Lexer::ternary`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum ColonRole {
+    /// not an operator, the `?` is an ordinary one at its level
+    None,
+    /// closes the `then` branch of `cond ? a : b`
+    #[default]
+    Ternary,
+    /// a binary operator binding tighter than every level, like `x: int`
+    Annotation,
+}
+
+/// A regex rule `Lexer::analyze_rules` saw match but never win.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RuleWarning {
+    /// the index in `res`
+    pub rule: usize,
+    /// what took the positions it matched at, a rule index or `None` for a literal
+    pub shadowed_by: Vec<Option<usize>>,
+}
+
+/// What `get_token` did at a position, recorded with `Lexer::trace` on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LexEvent {
+    /// an ignored char, or a newline not emitted
+    SkipIgnore { pos: usize },
+    MatchLiteral { start: usize, end: usize },
+    /// `rule` is the index in `res`
+    MatchRegex { rule: usize, start: usize, end: usize },
+    /// a char no rule took, handed to `Handler::on_unknown`
+    Unknown { pos: usize },
+    /// a newline, trivia, ws, comment, heredoc or string token, skipped comments included
+    Other { ty: &'static str, start: usize, end: usize },
+}
+
+/// One step of `Lexer::match_seq`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pat {
+    /// any token with this `type_name`
+    Type(&'static str),
+    /// a token with this `type_name` and this text
+    Value(&'static str, &'static str),
+}
+
+impl Pat {
+    pub fn matches(&self, token: &LexToken) -> bool {
+        match *self {
+            Pat::Type(ty) => token.type_name() == ty,
+            Pat::Value(ty, text) => token.same_token(ty, text),
+        }
+    }
+}
+
+/// Extra chars `get_token` skips, see `set_ignore_predicate`.
+#[derive(Clone)]
+pub struct LexIgnore(Arc<dyn Fn(char) -> bool + Send + Sync>);
+
+impl Debug for LexIgnore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LexIgnore")
+    }
+}
+
+/// every Unicode `White_Space` char, newlines and NBSP included, for `set_ignore_predicate`
+pub fn unicode_whitespace(c: char) -> bool {
+    c.is_whitespace()
+}
+
+/// Decodes string tokens into their `value`.
+#[derive(Clone)]
+pub struct LexDecoder(Arc<dyn EscapeDecoder + Send + Sync>);
+
+impl Debug for LexDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LexDecoder")
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LexString {
+    pub quote: &'static str,
+    pub ty: &'static str,
+    /// split `${...}` into `"interp"` groups between `"text"` tokens in `subs`
+    pub interpolate: bool,
+}
+
+/// Where an operator may stand, set per operator with `set_fixity`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LexFixity {
+    /// before its operand, where an operand is expected
+    pub prefix: bool,
+    /// between two operands
+    pub infix: bool,
+    /// after its operand, `on_postfix` evaluates it
+    pub postfix: bool,
+}
+
+/// `literals` as a set, rebuilt whenever the lexer finds `literals` replaced
+#[derive(Clone, Debug, Default)]
+struct LiteralSet {
+    src: String,
+    ascii: u128,
+    other: HashSet<char>,
+}
+
+impl LiteralSet {
+    fn new(src: &str) -> LiteralSet {
+        let mut set = LiteralSet { src: src.to_string(), ..Default::default() };
+        for c in src.chars() {
+            if c.is_ascii() {
+                set.ascii |= 1 << c as u32;
+            } else {
+                set.other.insert(c);
+            }
+        }
+        set
+    }
+
+    fn contains(&self, c: char) -> bool {
+        if c.is_ascii() {
+            self.ascii & 1 << c as u32 != 0
+        } else {
+            self.other.contains(&c)
+        }
+    }
+}
+
+/// A keyword construct like `if C then A else B`, each marker is followed by one expression.
+#[derive(Clone, Debug)]
+pub struct LexMixfix {
+    pub name: &'static str,
+    /// matched on the token text, the first one starts the construct
+    pub markers: Vec<&'static str>,
+    /// the `precs` level the last expression binds at, as for a prefix operator
+    pub prec: i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct LexComment {
+    pub start: &'static str,
+    /// `None` runs to the end of the line, the newline is not part of the comment
+    pub end: Option<&'static str>,
+    /// hand the comment out as a `"comment"` token instead of skipping it
+    pub emit: bool,
+}
+
+/// whether `c` is a right-to-left letter or a bidi control that reorders what follows
+fn is_bidi(c: char) -> bool {
+    matches!(c as u32,
+        0x0590..=0x08FF | 0xFB1D..=0xFDFF | 0xFE70..=0xFEFC | 0x10800..=0x10FFF | 0x1E800..=0x1EFFF
+        | 0x200E | 0x200F | 0x202A..=0x202E | 0x2066..=0x2069)
+}
+
+/// cells `c` takes in a monospace terminal, as the `unicode-width` crate counts them
+#[cfg(feature = "unicode-width")]
+fn char_width(c: char) -> usize {
+    unicode_width::UnicodeWidthChar::width(c).unwrap_or(1)
+}
+
+/// cells `c` takes in a monospace terminal, after the East Asian Width wide and fullwidth ranges
+#[cfg(not(feature = "unicode-width"))]
+fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036F | 0x1AB0..=0x1AFF | 0x1DC0..=0x1DFF | 0x200B..=0x200F | 0x20D0..=0x20FF
+        | 0xFE00..=0xFE0F | 0xFE20..=0xFE2F | 0xE0100..=0xE01EF => 0,
+        0x1100..=0x115F | 0x2E80..=0x303E | 0x3041..=0x33FF | 0x3400..=0x4DBF | 0x4E00..=0x9FFF
+        | 0xA000..=0xA4CF | 0xAC00..=0xD7A3 | 0xF900..=0xFAFF | 0xFE30..=0xFE4F | 0xFF00..=0xFF60
+        | 0xFFE0..=0xFFE6 | 0x1F300..=0x1F64F | 0x1F900..=0x1F9FF | 0x20000..=0x3FFFD => 2,
+        _ => 1,
+    }
+}
+
+/// What the source lines are indented with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IndentKind {
+    /// no line is indented
+    None,
+    Tabs,
+    Spaces,
+}
+
+/// The dominant indentation found by `detect_indent`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IndentStyle {
+    pub kind: IndentKind,
+    /// spaces per level, the gcd of all space indents, `1` for tabs
+    pub width: usize,
+    /// both tabs and spaces start some line
+    pub mixed: bool,
+}
+
+/// Pulls tokens from a `Lexer` one at a time, it stops after the first error.
+pub struct Tokens<'a, H: Handler> {
+    lexer: &'a mut Lexer<H>,
+    done: bool,
+}
+
+impl<H: Handler> Iterator for Tokens<'_, H> {
+    type Item = AstResult<LexToken>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let next = self.lexer.get_token().transpose();
+        self.done = !matches!(next, Some(Ok(_)));
+        next
+    }
+}
+
+impl<'a, H: Handler> Tokens<'a, H> {
+    /// split the stream on `sep` literals outside brackets, see `Statements`
+    pub fn statements(self, sep: &'static str) -> Statements<'a, H> {
+        Statements { tokens: self, sep }
+    }
+}
+
+/// `Tokens` cut into statements at each `sep` literal not inside brackets, the `sep` is dropped.
+/// An empty last statement is not yielded.
+pub struct Statements<'a, H: Handler> {
+    tokens: Tokens<'a, H>,
+    sep: &'static str,
+}
+
+impl<H: Handler> Iterator for Statements<'_, H> {
+    type Item = AstResult<Vec<LexToken>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut statement = vec![];
+        let mut depth = 0usize;
+        loop {
+            let token = match self.tokens.next() {
+                Some(Ok(token)) => token,
+                Some(Err(err)) => return Some(Err(err)),
+                None if statement.is_empty() => return None,
+                None => return Some(Ok(statement)),
+            };
+            if depth == 0 && token.same_token("lit", self.sep) {
+                return Some(Ok(statement));
+            }
+            if token.is_open_bracket(self.tokens.lexer) {
+                depth += 1;
+            } else if token.is_close_bracket(self.tokens.lexer) {
+                depth = depth.saturating_sub(1);
+            }
+            statement.push(token);
+        }
+    }
+}
+
+/// `Tokens` with each token's line and `column`.
+pub struct TokenPositions<'a, H: Handler>(Tokens<'a, H>);
+
+impl<H: Handler> Iterator for TokenPositions<'_, H> {
+    type Item = AstResult<(LexToken, usize, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.0.next()?.map(|token| {
+            let col = self.0.lexer.column(token.start);
+            let line = token.lineno;
+            (token, line, col)
+        }))
+    }
+}
+
+/// Where a rule's leftmost match at or after `from` starts and ends, for the source and pattern
+/// it was found in. No match of the rule starts between `from` and `found`.
+#[derive(Clone, Debug)]
+struct RegexProbe {
+    from: usize,
+    source: (usize, usize),
+    pattern: usize,
+    found: Option<(usize, usize)>,
+}
+
+/// A position and its line number, `get_now_lineno` counts only the newlines between it and
+/// the position asked for and moves it there.
+#[derive(Debug)]
+struct LineCursor(Mutex<(usize, usize)>);
+
+impl Clone for LineCursor {
+    fn clone(&self) -> Self {
+        LineCursor(Mutex::new(*self.0.lock().unwrap()))
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct Lexer<H>
 where H: Handler {
     pub res: Vec<LexRegex>,
+    /// `res` indices highest priority first, rebuilt by `match_regex` once it no longer fits `res`
+    rule_order: Vec<usize>,
+    /// per rule the last search `match_regex` made, so a rule matching nowhere near is not
+    /// searched for again through the rest of the source at every token
+    probes: Vec<Option<RegexProbe>>,
+    pub match_policy: MatchPolicy,
     pub data: Arc<String>,
     pub tokenstack: Vec<LexToken>,
     pub wait_token: Vec<LexToken>,
     pub pos: usize,
     pub len: usize,
+    /// `data` is pure ASCII so `get_next_pos` steps one byte at a time
+    pub is_ascii: bool,
     pub handler: H,
     pub ignore: &'static str,
-    pub literals: &'static str,
-    pub hash_matchs: HashMap<(&'static str, &'static str), &'static str>,
+    /// skipped like `ignore` when it accepts the char
+    pub ignore_predicate: Option<LexIgnore>,
+    /// the single char literals, owned once a runtime string was set
+    pub literals: Cow<'static, str>,
+    /// literals longer than one char, like `->`, taken over the single char ones
+    pub multi_literals: Vec<&'static str>,
+    literal_set: LiteralSet,
+    /// a literal beats a regex match of the same length, the longer one always wins
+    pub literal_priority: bool,
+    /// `(ty, opener)` to the closers of the same type accepted for it
+    pub hash_matchs: HashMap<(&'static str, &'static str), Vec<&'static str>>,
     pub precs: Vec<LexPrec>,
-    prec_hash: HashMap<(&'static str, &'static str), (bool, i32)>,
+    pub strings: Vec<LexString>,
+    pub comments: Vec<LexComment>,
+    /// when set, string tokens and interpolation `"text"` carry the decoded `AstAny::Str`
+    pub escape_decoder: Option<LexDecoder>,
+    /// `(marker, ty)`, `<<END` reads every line up to the one holding `END` as one token
+    pub heredocs: Vec<(&'static str, &'static str)>,
+    /// `id` tokens whose text is a keyword take the keyword's type
+    pub keywords: HashMap<&'static str, &'static str>,
+    /// match keywords on the lowercased text, register them in lowercase
+    pub case_insensitive_keywords: bool,
+    /// chars an `id` token may also continue with, see `set_ident_extra`
+    pub ident_extra: String,
+    /// the most chars an `id` token may have, keywords are not held to it
+    pub max_identifier_len: Option<usize>,
+    /// emit `\n` as a `"newline"` token instead of skipping it
+    pub emit_newline: bool,
+    /// merge a run of newlines (blank lines included) into one `"newline"` token
+    pub collapse_newline: bool,
+    /// emit the whitespace otherwise skipped as `"trivia"` tokens, one per run of a repeated
+    /// char with the run length as `AstAny::Usize`, so `LexToken::to_source` gives the input back
+    pub trivia: bool,
+    /// emit each run of `ignore` chars as one `"ws"` token, for grammars where spacing separates
+    pub emit_ws: bool,
+    /// the group evaluated as `AstAny::List`, its elements split by `list_sep`
+    pub list_group: (&'static str, &'static str),
+    pub list_sep: &'static str,
+    /// the group that makes a call when it follows an operand, like `f(a, b)`
+    pub call_group: (&'static str, &'static str),
+    /// the literal ending a top-level statement for `eval_all`
+    pub terminator: &'static str,
+    /// `(ty, "?", ":")` of the conditional operator, its precedence is the level listing the `?`
+    pub ternary: (&'static str, &'static str, &'static str),
+    pub colon_role: ColonRole,
+    pub mixfix: Vec<LexMixfix>,
+    /// operators not listed stand infix at the level `precs` gives them, and prefix too when
+    /// they are in `prefix_ops`
+    pub fixities: HashMap<(&'static str, &'static str), LexFixity>,
+    /// the operators that may stand before their operand, see `add_prefix_operator`
+    pub prefix_ops: Vec<(&'static str, &'static str)>,
+    /// every operator of `precs` may also stand prefix, so `a + * b` is `a + (* b)` instead of
+    /// failing at the `*`, see `set_lenient_operators`
+    pub lenient_operators: bool,
+    /// the operator whose left operand names the target of `Handler::on_assign`
+    pub assign: (&'static str, &'static str),
+    /// lines starting with it hold `if`/`ifdef`/`ifndef`/`else`/`endif` directives on `defines`
+    pub directive_prefix: Option<&'static str>,
+    pub defines: HashSet<String>,
+    /// the open `if` directives and whether their current branch is included
+    directives: Vec<(LexToken, bool)>,
+    /// a position `get_token` reached and whether only `ignore` chars stand before it on its line
+    line_state: (usize, bool),
+    line_cursor: LineCursor,
+    /// run in registration order over every raw token in `parser_token`
+    pub transformers: Vec<LexTransformer>,
+    /// types handed out by transformers or `Handler::on_unknown`, for `known_types`
+    pub extra_types: Vec<&'static str>,
+    /// nesting allowed while parsing and evaluating before `AstError` instead of a stack overflow
+    pub max_depth: usize,
+    /// set by `new_preprocessed`, maps `data` offsets back to the caller's text
+    pub source_map: Option<SourceMap>,
+    /// set by `from_utf16`, maps `data` offsets to UTF-16 code units
+    pub utf16_map: Option<SourceMap>,
+    /// a tab moves `column` to the next multiple of this
+    pub tab_width: usize,
+    /// `column` counts full width chars as two cells and combining marks as none
+    pub wide_columns: bool,
+    /// at EOF `parser_token` closes still open groups with empty synthetic closers instead of failing
+    pub recover_brackets: bool,
+    /// the openers `recover_brackets` had to close in the last `group`, innermost first
+    pub diagnostics: Vec<LexToken>,
+    /// groups `feed_line` saw open but not yet closed, outermost first
+    fed_groups: Vec<LexToken>,
+    /// the most tokens `tokenize`, `tokenize_range` and `lex` collect before failing
+    pub max_tokens: Option<usize>,
+    /// the most bytes a regex rule may match from a token's start, a longer match is given up
+    /// and its `(rule, start)` kept in `overruns`
+    pub match_window: Option<usize>,
+    pub overruns: Vec<(usize, usize)>,
+    /// record a `LexEvent` for each step of `get_token`, see `take_trace`
+    pub trace: bool,
+    events: Vec<LexEvent>,
+    /// the type names known only at runtime, see `add_regex_named`
+    pub types: TypeInterner,
+    /// operator spellings parsed as the canonical op they map to
+    pub aliases: HashMap<&'static str, &'static str>,
+    /// expected token count, token lists are allocated with room for this many
+    pub capacity_hint: usize,
+    /// when set, `token_text` keeps the text of each span it was asked for
+    pub text_cache: Option<HashMap<(usize, usize), Arc<str>>>,
+    /// the most chars of a source line `render_error` shows, cut around the caret
+    pub max_render_width: Option<usize>,
+    /// the file the source came from, `render_error` leads with it
+    pub name: Option<Arc<str>>,
+    /// handler calls `eval_budget` has left
+    pub(crate) budget: Option<usize>,
+    pub(crate) prec_hash: HashMap<(&'static str, &'static str), (bool, i32)>,
+    pub(crate) prefix_hash: HashMap<(&'static str, &'static str), i32>,
 }
 
 // impl Default for Lexer<DefaultHandler> {
@@ -114,132 +873,1281 @@ where H: Handler {
 
 impl<H> Lexer<H> where H: Handler {
     pub fn new(data: String, handler: H) -> Lexer<H> {
+        let len = data.len();
+        let is_ascii = data.is_ascii();
         let mut lex = Lexer {
             res: vec![],
+            rule_order: vec![],
+            probes: vec![],
+            match_policy: MatchPolicy::FirstMatch,
             data: Arc::new(data),
             tokenstack: vec![],
             wait_token: vec![],
             pos: 0,
-            len: 0,
-            handler: handler,
+            len,
+            is_ascii,
+            handler,
             ignore: " \t",
-            literals: "+-*/%^<>=!?()[]{}.,;:",
+            ignore_predicate: None,
+            literals: "+-*/%^<>=!?()[]{}.,;:".into(),
+            multi_literals: vec![],
+            literal_set: LiteralSet::default(),
+            literal_priority: true,
             hash_matchs: HashMap::from([
-                (("lit", "("), ")"),
-                (("lit", "{"), "}"),
-                (("lit", "["), "]"),
+                (("lit", "("), vec![")"]),
+                (("lit", "{"), vec!["}"]),
+                (("lit", "["), vec!["]"]),
             ]),
             precs: vec![
+                LexPrec::new("lit", false, vec!["="]),
+                LexPrec::new("lit", false, vec!["?"]),
                 LexPrec::new("lit", true, vec!["+", "-"]),
                 LexPrec::new("lit", true, vec!["*", "/"]),
                 LexPrec::new("lit", false, vec!["-"]),
             ],
+            strings: vec![],
+            comments: vec![],
+            escape_decoder: None,
+            heredocs: vec![],
+            keywords: HashMap::new(),
+            case_insensitive_keywords: false,
+            ident_extra: String::new(),
+            max_identifier_len: None,
+            emit_newline: false,
+            collapse_newline: false,
+            trivia: false,
+            emit_ws: false,
+            list_group: ("lit", "["),
+            list_sep: ",",
+            call_group: ("lit", "("),
+            terminator: ";",
+            ternary: ("lit", "?", ":"),
+            colon_role: ColonRole::Ternary,
+            assign: ("lit", "="),
+            mixfix: vec![],
+            fixities: HashMap::new(),
+            prefix_ops: vec![("lit", "-")],
+            lenient_operators: false,
+            directive_prefix: None,
+            defines: HashSet::new(),
+            directives: vec![],
+            line_state: (0, true),
+            line_cursor: LineCursor(Mutex::new((0, 1))),
+            transformers: vec![],
+            extra_types: vec![],
+            max_depth: 64,
+            source_map: None,
+            utf16_map: None,
+            tab_width: 4,
+            wide_columns: false,
+            recover_brackets: false,
+            diagnostics: vec![],
+            fed_groups: vec![],
+            max_tokens: None,
+            match_window: None,
+            overruns: vec![],
+            trace: false,
+            events: vec![],
+            types: TypeInterner::default(),
+            aliases: HashMap::new(),
+            capacity_hint: 0,
+            text_cache: None,
+            max_render_width: None,
+            name: None,
+            budget: None,
             prec_hash: HashMap::new(),
+            prefix_hash: HashMap::new(),
         };
         lex.do_analyse_prec();
         lex
     }
 
+    pub fn new_preprocessed<P: Preprocessor>(data: String, handler: H, preprocessor: &P) -> Lexer<H> {
+        let (data, map) = preprocessor.process(&data);
+        let mut lex = Lexer::new(data, handler);
+        lex.source_map = Some(map);
+        lex
+    }
+
+    /// `new` for the source of the file `name`
+    pub fn new_named(data: String, handler: H, name: impl Into<Arc<str>>) -> Lexer<H> {
+        let mut lex = Lexer::new(data, handler);
+        lex.name = Some(name.into());
+        lex
+    }
+
+    /// Lex UTF-8 bytes, `InvalidEncoding` holds the offset of the first byte that is not valid UTF-8.
+    pub fn from_bytes(bytes: Vec<u8>, handler: H) -> AstResult<Lexer<H>> {
+        let text = String::from_utf8(bytes).map_err(|e| AstError::new_invalid_encoding_error(e.utf8_error().valid_up_to()))?;
+        Ok(Lexer::new(text, handler))
+    }
+
+    /// Lex UTF-16 input, it is decoded into `data` and `utf16_span` maps tokens back to code units.
+    /// A leading byte order mark is not part of `data`, offsets still count it as unit `0`.
+    pub fn from_utf16(data: &[u16], handler: H) -> AstResult<Lexer<H>> {
+        let mut text = String::with_capacity(data.len());
+        let mut map = SourceMap::new();
+        let mut unit = 0;
+        if data.first() == Some(&0xFEFF) {
+            unit = 1;
+            map.add_mapping(0, unit);
+        }
+        for ch in char::decode_utf16(data[unit..].iter().copied()) {
+            let ch = ch.map_err(|_| AstError::new_invalid_encoding_error(unit))?;
+            text.push(ch);
+            unit += ch.len_utf16();
+            if !ch.is_ascii() {
+                map.add_mapping(text.len(), unit);
+            }
+        }
+        let mut lex = Lexer::new(text, handler);
+        lex.utf16_map = Some(map);
+        Ok(lex)
+    }
+
+    /// the token's `(start, end)` in UTF-16 code units
+    pub fn utf16_span(&self, token: &LexToken) -> (usize, usize) {
+        (self.utf16_offset(token.start), self.utf16_offset(token.end))
+    }
+
+    /// the UTF-16 code unit offset of the byte offset `pos`
+    pub fn utf16_offset(&self, pos: usize) -> usize {
+        match &self.utf16_map {
+            Some(map) => map.to_original(pos),
+            None => self.data[..pos].encode_utf16().count(),
+        }
+    }
+
+    /// the token's `(start, end)` in the text given before preprocessing
+    pub fn original_span(&self, token: &LexToken) -> (usize, usize) {
+        match &self.source_map {
+            Some(map) => (map.to_original(token.start), map.to_original(token.end)),
+            None => (token.start, token.end),
+        }
+    }
+
+    /// an operator used infix binds at the first level listing it, one of `prefix_ops` used
+    /// prefix at the last one, so the seeded `-` is binary at level 2 and unary at level 4
     fn do_analyse_prec(&mut self) {
         let mut hash = HashMap::new();
+        let mut prefix = HashMap::new();
         for idx in 0..self.precs.len() {
             let value = &self.precs[idx];
             for p in &value.precs {
-                hash.insert((value.ty, *p), (value.left, idx as i32));
+                hash.entry((value.ty, *p)).or_insert((value.left, idx as i32));
+                if self.lenient_operators || self.prefix_ops.contains(&(value.ty, *p)) {
+                    prefix.insert((value.ty, *p), idx as i32);
+                }
             }
         }
         self.prec_hash = hash;
+        self.prefix_hash = prefix;
     }
 
-    pub fn add_regex(&mut self, ty: &'static str, re: Regex) {
-        let reg = LexRegex {
-            ty, re
-        };
-        self.res.push(reg);
+    /// Parse an `alias` token, of any type, with the precedence of `canonical` from `precs`.
+    /// The operator node keeps the alias text and carries `canonical` for `op_text`.
+    pub fn add_operator_alias(&mut self, alias: &'static str, canonical: &'static str) {
+        self.aliases.insert(alias, canonical);
     }
 
-    pub fn add_hash_match(&mut self, ty: &'static str, start: &'static str, end: &'static str, ) {
-        self.hash_matchs.insert((ty, start), end);
+    /// the `(ty, op)` an operator token is looked up by, aliases resolve to their canonical op
+    pub(crate) fn op_key<'a>(&self, token: &'a LexToken) -> (&'static str, &'a str) {
+        if let Some(&canonical) = self.aliases.get(token.get_value()) {
+            if let Some(level) = self.precs.iter().find(|p| p.precs.contains(&canonical)) {
+                return (level.ty, canonical);
+            }
+        }
+        (token.ty, token.get_value())
     }
 
-    pub fn get_next_pos(&self, ori: usize) -> Option<usize> {
-        let bytes = self.data.as_bytes();
-        if ori >= bytes.len() {
-            return None;
+    /// The `precs` levels one line each, lowest binding first, as `2 left: + -`, then the
+    /// operators standing prefix by the level they bind at, as `prefix 2: -`. Operators of a
+    /// type other than `lit` show as `ty:op`, one listed more than once is followed by the
+    /// other levels listing it, as `- (also 4)`.
+    pub fn dump_precedence(&self) -> String {
+        let name = |ty: &str, op: &str| if ty == "lit" { op.to_string() } else { format!("{}:{}", ty, op) };
+        let mut listed: HashMap<(&str, &str), Vec<usize>> = HashMap::new();
+        for (idx, level) in self.precs.iter().enumerate() {
+            for op in &level.precs {
+                listed.entry((level.ty, op)).or_default().push(idx);
+            }
         }
-        let mut byte = bytes[ori];
-        let mut byte_len = 0;
-        loop {
-            if byte.bitand(0x80) == 0 {
-                println!("byte = {} break", byte);
-                break;
+        let mut lines = vec![];
+        for (idx, level) in self.precs.iter().enumerate() {
+            let mut line = format!("{} {}:", idx, if level.left { "left" } else { "right" });
+            for op in &level.precs {
+                line.push(' ');
+                line.push_str(&name(level.ty, op));
+                let mut others = listed[&(level.ty, *op)].clone();
+                others.remove(others.iter().position(|l| *l == idx).unwrap());
+                if !others.is_empty() {
+                    let others: Vec<_> = others.iter().map(|l| l.to_string()).collect();
+                    line.push_str(&format!(" (also {})", others.join(" ")));
+                }
             }
-            byte_len += 1;
-            byte = byte.checked_shl(1).unwrap_or(0);
+            lines.push(line);
         }
-        byte_len = byte_len.max(1);
-
-        let pos = ori + byte_len;
-        if pos > bytes.len() {
-            None
-        } else {
-            Some(pos)
+        let mut prefix: Vec<(i32, String)> = self.prefix_hash.iter().map(|((ty, op), prec)| (*prec, name(ty, op))).collect();
+        prefix.sort();
+        for (idx, (prec, op)) in prefix.iter().enumerate() {
+            if idx == 0 || prefix[idx - 1].0 != *prec {
+                lines.push(format!("prefix {}:", prec));
+            }
+            let line = lines.last_mut().unwrap();
+            line.push(' ');
+            line.push_str(op);
         }
+        lines.join("\n")
     }
 
-    pub fn get_now_lineno(&self, pos: usize) -> usize {
-        self.data[0..pos].matches("\n").count() + 1
+    /// replace the precedence levels, lowest binding first, and rebuild the operator tables
+    pub fn set_precs(&mut self, precs: Vec<LexPrec>) {
+        self.precs = precs;
+        self.do_analyse_prec();
     }
 
-    pub fn get_token(&mut self) -> Option<LexToken> {
-        let mut ori = self.pos;
-        loop {
-            let pos = self.get_next_pos(ori);
-            println!("ori = {} pos = {:?}", ori, pos);
-            if pos.is_none() {
-                return None;
-            }
-            let val = self.data.get(ori .. pos.unwrap()).unwrap();
-            if self.ignore.contains(val) {
-                self.pos = pos.unwrap();
-                ori = pos.unwrap();
+    /// Find regex rules that look unreachable, by trying every rule at each char of `data` that
+    /// is not ignored. A rule that matches somewhere but always loses to another rule or a literal
+    /// under the current `match_policy`, priorities and `literal_priority` is reported.
+    pub fn analyze_rules(&self) -> Vec<RuleWarning> {
+        let data = self.data.as_str();
+        let literals = LiteralSet::new(&self.literals);
+        let mut order: Vec<usize> = (0..self.res.len()).collect();
+        order.sort_by_key(|idx| std::cmp::Reverse(self.res[*idx].priority));
+        let mut won = vec![false; self.res.len()];
+        let mut lost: Vec<Vec<Option<usize>>> = vec![vec![]; self.res.len()];
+        for (ori, ch) in data.char_indices() {
+            if self.is_ignored(&data[ori..ori + ch.len_utf8()]) {
                 continue;
             }
-
-            if let Some(_lpos) = self.literals.find(val) {
-                self.pos = pos.unwrap();
-                return Some(LexToken {
-                    ty: "lit",
-                    data: self.data.clone(),
-                    lineno: self.get_now_lineno(ori),
-                    start: ori,
-                    end: pos.unwrap(),
-                    subs: vec![],
-                    value: AstAny::Unknow,
-                })
+            let found: Vec<(usize, usize)> = order.iter().filter_map(|&idx| {
+                let m = self.res[idx].re.find_at(data, ori)?;
+                (m.start() == ori && m.end() > ori).then_some((idx, m.end()))
+            }).collect();
+            let best = match self.match_policy {
+                MatchPolicy::FirstMatch => found.first().copied(),
+                MatchPolicy::LongestMatch => found.iter().copied().reduce(|a, b| if b.1 > a.1 { b } else { a }),
+            };
+            let Some((rule, end)) = best else {
+                continue;
+            };
+            let multi = self.multi_literals.iter().filter(|l| data[ori..].starts_with(**l)).map(|l| ori + l.len()).max();
+            let literal = multi.or_else(|| literals.contains(ch).then_some(ori + ch.len_utf8()));
+            let winner = match literal {
+                Some(lit) if lit > end || (lit == end && self.literal_priority) => None,
+                _ => Some(rule),
+            };
+            for (idx, _) in found {
+                if winner == Some(idx) {
+                    won[idx] = true;
+                } else if !lost[idx].contains(&winner) {
+                    lost[idx].push(winner);
+                }
             }
+        }
+        lost.into_iter().enumerate()
+            .filter(|(rule, by)| !won[*rule] && !by.is_empty())
+            .map(|(rule, shadowed_by)| RuleWarning { rule, shadowed_by })
+            .collect()
+    }
+
+    /// Add `symbol` as a right associative power operator binding tighter than every other level,
+    /// so `-a ^ b` is `-(a ^ b)`. The symbol is made a literal when it is not one yet, and taken
+    /// off the levels already listing it, so calling this again changes nothing.
+    pub fn enable_power_operator(&mut self, symbol: &'static str) {
+        if symbol.chars().count() > 1 {
+            self.add_literal(symbol);
+        } else if !self.literals.contains(symbol) {
+            self.literals.to_mut().push_str(symbol);
+        }
+        let mut precs = std::mem::take(&mut self.precs);
+        for level in precs.iter_mut().filter(|level| level.ty == "lit") {
+            level.precs.retain(|op| *op != symbol);
+        }
+        precs.retain(|level| !level.precs.is_empty());
+        precs.push(LexPrec::new("lit", false, vec![symbol]));
+        self.set_precs(precs);
+    }
+
+    /// `add_regex` with a type name built at runtime, interned through `types`. The tokens
+    /// have `ty` `"named"` and carry the name in `LexToken::name`.
+    pub fn add_regex_named(&mut self, ty: &str, re: Regex) -> TypeName {
+        let name = self.types.intern(ty);
+        self.res.push(LexRegex { name: Some(name.clone()), ..LexRegex::new("named", re) });
+        name
+    }
+
+    pub fn add_regex(&mut self, ty: &'static str, re: Regex) {
+        self.res.push(LexRegex::new(ty, re));
+    }
+
+    /// Reserve room for about `approx_tokens` tokens so big sources do not keep reallocating.
+    pub fn with_capacity_hint(&mut self, approx_tokens: usize) {
+        self.capacity_hint = approx_tokens;
+        self.tokenstack.reserve(approx_tokens);
+        self.wait_token.reserve(approx_tokens.min(64));
+    }
+
+    /// The text of `token` as a shared string, with `text_cache` on a span read before hands back
+    /// the same `Arc`. The cache is keyed by offsets so it must be cleared when `data` is replaced.
+    pub fn token_text(&mut self, token: &LexToken) -> Arc<str> {
+        match self.text_cache.as_mut() {
+            Some(cache) => cache.entry((token.start, token.end)).or_insert_with(|| token.get_value().into()).clone(),
+            None => token.get_value().into(),
+        }
+    }
+
+    /// Let every operator stand before its operand as well, as `add_prefix_operator` would.
+    pub fn set_lenient_operators(&mut self, lenient: bool) {
+        self.lenient_operators = lenient;
+        self.do_analyse_prec();
+    }
+
+    /// Let `(ty, op)` stand before its operand, binding at the last `precs` level listing it.
+    pub fn add_prefix_operator(&mut self, ty: &'static str, op: &'static str) {
+        if !self.prefix_ops.contains(&(ty, op)) {
+            self.prefix_ops.push((ty, op));
+        }
+        self.do_analyse_prec();
+    }
+
+    /// Say where `(ty, op)` may stand, it still needs a `precs` level to bind at.
+    /// A postfix op binds at its infix level, and one that may also be infix is
+    /// taken as infix whenever an operand follows it.
+    pub fn set_fixity(&mut self, ty: &'static str, op: &'static str, prefix: bool, infix: bool, postfix: bool) {
+        self.fixities.insert((ty, op), LexFixity { prefix, infix, postfix });
+        if prefix {
+            self.add_prefix_operator(ty, op);
+        }
+    }
+
+    pub(crate) fn fixity(&self, token: &LexToken) -> LexFixity {
+        let key = self.op_key(token);
+        match self.fixities.get(&key) {
+            Some(fixity) => *fixity,
+            None => LexFixity {
+                prefix: self.prefix_hash.contains_key(&key),
+                infix: self.prec_hash.contains_key(&key),
+                postfix: false,
+            },
+        }
+    }
+
+    pub fn add_mixfix(&mut self, name: &'static str, markers: Vec<&'static str>, prec: i32) {
+        self.mixfix.push(LexMixfix { name, markers, prec });
+    }
+
+    /// `add_regex` with an explicit `priority` instead of relying on registration order
+    pub fn add_regex_prio(&mut self, ty: &'static str, re: Regex, priority: i32) {
+        self.res.push(LexRegex::with_priority(ty, re, priority));
+    }
+
+    /// `add_regex` whose tokens carry the spans of the capture groups in `captures`
+    pub fn add_regex_capturing(&mut self, ty: &'static str, re: Regex) {
+        self.res.push(LexRegex { capturing: true, ..LexRegex::new(ty, re) });
+    }
+
+    /// replace every regex rule at once
+    pub fn set_regexes(&mut self, rules: Vec<LexRegex>) {
+        self.res = rules;
+    }
+
+    pub fn add_hash_match(&mut self, ty: &'static str, start: &'static str, end: &'static str, ) {
+        self.hash_matchs.insert((ty, start), vec![end]);
+    }
+
+    /// Accept `end` as one more closer for `start`, on top of those already registered.
+    pub fn add_hash_closer(&mut self, ty: &'static str, start: &'static str, end: &'static str) {
+        let ends = self.hash_matchs.entry((ty, start)).or_default();
+        if !ends.contains(&end) {
+            ends.push(end);
+        }
+    }
+
+    /// whether `token` closes a group opened by `opener`
+    pub(crate) fn is_closer_of(&self, opener: &LexToken, token: &LexToken) -> bool {
+        opener.ty == token.ty
+            && self.hash_matchs.get(&(opener.ty, opener.get_value())).is_some_and(|ends| ends.contains(&token.get_value()))
+    }
+
+    pub fn add_string(&mut self, quote: &'static str, ty: &'static str) {
+        self.strings.push(LexString { quote, ty, interpolate: false });
+    }
+
+    pub fn add_interp_string(&mut self, quote: &'static str, ty: &'static str) {
+        self.strings.push(LexString { quote, ty, interpolate: true });
+    }
+
+    pub fn set_escape_decoder<D>(&mut self, decoder: D)
+    where D: EscapeDecoder + Send + Sync + 'static {
+        self.escape_decoder = Some(LexDecoder(Arc::new(decoder)));
+    }
+
+    /// a `ty` token over `start..end` holding the decoded text when a decoder is set
+    fn new_text_token(&self, ty: &'static str, start: usize, end: usize, text_start: usize, text_end: usize) -> AstResult<LexToken> {
+        let mut token = self.new_token(ty, start, end);
+        if let Some(decoder) = &self.escape_decoder {
+            token.value = AstAny::Str(decoder.0.decode(&self.data[text_start..text_end], text_start)?);
+        }
+        Ok(token)
+    }
+
+    /// Check `ignore` does not hide a literal, `get_token` skips ignored chars first.
+    pub fn validate_config(&self) -> AstResult<()> {
+        let firsts = self.multi_literals.iter().filter_map(|l| l.chars().next());
+        match self.literals.chars().chain(firsts).find(|c| self.ignore.contains(*c)) {
+            Some(c) => Err(AstError::new_config_conflict_error(c)),
+            None => Ok(()),
+        }
+    }
+
+    /// Also skip every char `f` accepts, line numbers still count the newlines it skips.
+    pub fn set_ignore_predicate(&mut self, f: impl Fn(char) -> bool + Send + Sync + 'static) {
+        self.ignore_predicate = Some(LexIgnore(Arc::new(f)));
+    }
+
+    /// whether `get_token` skips the char `val`
+    fn is_ignored(&self, val: &str) -> bool {
+        self.ignore.contains(val)
+            || self.ignore_predicate.as_ref().is_some_and(|f| val.chars().next().is_some_and(|c| (f.0)(c)))
+    }
+
+    /// set `ignore`, refused when it takes chars from the literals
+    pub fn set_ignore(&mut self, ignore: &'static str) -> AstResult<()> {
+        let old = std::mem::replace(&mut self.ignore, ignore);
+        self.validate_config().inspect_err(|_| self.ignore = old)
+    }
+
+    /// set `literals`, refused when one of them is ignored
+    pub fn set_literals(&mut self, literals: impl Into<Cow<'static, str>>) -> AstResult<()> {
+        let old = std::mem::replace(&mut self.literals, literals.into());
+        self.validate_config().inspect_err(|_| self.literals = old)
+    }
+
+    /// `set_literals` from single chars, each char is a literal whatever it is
+    pub fn set_literals_chars(&mut self, chars: &[char]) -> AstResult<()> {
+        self.set_literals(chars.iter().collect::<String>())
+    }
+
+    pub fn add_literal(&mut self, literal: &'static str) {
+        if literal.chars().count() > 1 && !self.multi_literals.contains(&literal) {
+            self.multi_literals.push(literal);
+        }
+    }
+
+    /// the end of the longest literal at `ori`, `pos` being the end of its first char
+    fn match_literal(&self, ori: usize, pos: usize) -> Option<usize> {
+        let rest = &self.data[ori..self.len];
+        let multi = self.multi_literals.iter().filter(|l| rest.starts_with(**l)).map(|l| ori + l.len()).max();
+        let single = self.data[ori..pos].chars().next().is_some_and(|c| self.literal_set.contains(c));
+        multi.or_else(|| single.then_some(pos))
+    }
+
+    pub fn add_line_comment(&mut self, start: &'static str, emit: bool) {
+        self.comments.push(LexComment { start, end: None, emit });
+    }
+
+    pub fn add_block_comment(&mut self, start: &'static str, end: &'static str, emit: bool) {
+        self.comments.push(LexComment { start, end: Some(end), emit });
+    }
+
+    /// the comment starting at `ori` as its end and whether to emit it
+    fn match_comment(&self, ori: usize) -> AstResult<Option<(usize, bool)>> {
+        let rest = &self.data[ori..self.len];
+        let Some(comment) = self.comments.iter().find(|c| rest.starts_with(c.start)) else {
+            return Ok(None);
+        };
+        let body = ori + comment.start.len();
+        let end = match comment.end {
+            None => self.data[body..self.len].find('\n').map_or(self.len, |p| body + p),
+            Some(end) => match self.data[body..self.len].find(end) {
+                Some(p) => body + p + end.len(),
+                None => return Err(AstError::new_no_match_close_error(self.new_token("comment", ori, body))),
+            },
+        };
+        Ok(Some((end, comment.emit)))
+    }
+
+    /// Consume from `pos` up to the next `delim` as a `"raw"` token, `delim` is taken along
+    /// only with `include_delim`. Fails at EOF without moving when `delim` never comes.
+    pub fn scan_until(&mut self, delim: &str, include_delim: bool) -> AstResult<LexToken> {
+        let start = self.pos;
+        let Some(p) = self.data[start..self.len].find(delim) else {
+            return Err(AstError::new_unexpected_eof_error());
+        };
+        let end = start + p + if include_delim { delim.len() } else { 0 };
+        self.pos = end;
+        Ok(self.new_token("raw", start, end))
+    }
+
+    pub fn add_heredoc(&mut self, marker: &'static str, ty: &'static str) {
+        self.heredocs.push((marker, ty));
+    }
+
+    pub fn add_directive_prefix(&mut self, prefix: &'static str) {
+        self.directive_prefix = Some(prefix);
+    }
+
+    pub fn define(&mut self, name: &str) {
+        self.defines.insert(name.to_string());
+    }
+
+    pub fn add_transformer<F>(&mut self, f: F)
+    where F: FnMut(LexToken) -> Vec<LexToken> + Send + 'static {
+        self.transformers.push(LexTransformer(Arc::new(Mutex::new(f))));
+    }
 
-            for re in &self.res {
-                if let Some(p) = re.re.find_at(&self.data, ori) {
-                    if p.start() != ori {
-                        continue;
+    /// feed the token through each transformer, every output goes on to the next one
+    fn transform(&self, token: LexToken) -> Vec<LexToken> {
+        let mut tokens = vec![token];
+        for transformer in &self.transformers {
+            let mut f = transformer.0.lock().unwrap();
+            tokens = tokens.into_iter().flat_map(&mut *f).collect();
+        }
+        tokens
+    }
+
+    pub fn add_keyword(&mut self, word: &'static str, ty: &'static str) {
+        self.keywords.insert(word, ty);
+    }
+
+    /// Let `id` tokens run on through `chars`, as in `font-size` or `list?`.
+    /// A run of them is only taken when the id rule matches again right after it
+    /// or no word char follows, so `a - b` and `a-1` still lex apart.
+    pub fn set_ident_extra(&mut self, chars: &str) {
+        self.ident_extra = chars.to_string();
+    }
+
+    /// the end of the `id` match of rule `rule` ending at `end` once `ident_extra` runs are taken in
+    fn extend_ident(&self, rule: usize, mut end: usize) -> usize {
+        if self.ident_extra.is_empty() || self.res[rule].ty != "id" {
+            return end;
+        }
+        let data = &self.data[..self.len];
+        loop {
+            let rest = &data[end..];
+            let run: usize = rest.chars().take_while(|c| self.ident_extra.contains(*c)).map(char::len_utf8).sum();
+            if run == 0 {
+                return end;
+            }
+            let next = end + run;
+            match self.res[rule].re.find_at(data, next) {
+                Some(m) if m.start() == next && m.end() > next => end = m.end(),
+                _ if data[next..].chars().next().is_none_or(|c| !c.is_alphanumeric() && c != '_') => return next,
+                _ => return end,
+            }
+        }
+    }
+
+    fn keyword_type(&self, ty: &'static str, text: &str) -> &'static str {
+        if ty != "id" {
+            return ty;
+        }
+        let found = if self.case_insensitive_keywords {
+            self.keywords.get(text.to_lowercase().as_str())
+        } else {
+            self.keywords.get(text)
+        };
+        found.copied().unwrap_or(ty)
+    }
+
+    /// the leftmost match of rule `idx` at or after `ori`, taken from its last search when that
+    /// began no later and found nothing before `ori`
+    fn probe_regex(&mut self, idx: usize, ori: usize) -> Option<(usize, usize)> {
+        if self.probes.len() != self.res.len() {
+            self.probes = vec![None; self.res.len()];
+        }
+        let re = &self.res[idx].re;
+        let source = (Arc::as_ptr(&self.data) as usize, self.len);
+        let pattern = re.as_str().as_ptr() as usize;
+        if let Some(probe) = &self.probes[idx] {
+            if probe.source == source && probe.pattern == pattern && probe.from <= ori
+                && probe.found.is_none_or(|(start, _)| start >= ori) {
+                return probe.found;
+            }
+        }
+        let found = re.find_at(&self.data[..self.len], ori).map(|m| (m.start(), m.end()));
+        self.probes[idx] = Some(RegexProbe { from: ori, source, pattern, found });
+        found
+    }
+
+    pub fn get_next_pos(&self, ori: usize) -> Option<usize> {
+        let bytes = self.data.as_bytes();
+        if ori >= self.len {
+            return None;
+        }
+        if self.is_ascii {
+            return Some(ori + 1);
+        }
+        let mut byte = bytes[ori];
+        let mut byte_len = 0;
+        loop {
+            if byte.bitand(0x80) == 0 {
+                break;
+            }
+            byte_len += 1;
+            byte = byte.checked_shl(1).unwrap_or(0);
+        }
+        byte_len = byte_len.max(1);
+
+        let pos = ori + byte_len;
+        if pos > self.len {
+            None
+        } else {
+            Some(pos)
+        }
+    }
+
+    pub fn ctx(&self) -> LexerCtx<'_> {
+        LexerCtx { data: &self.data, prec_hash: &self.prec_hash }
+    }
+
+    /// 1-based line and char column of the byte offset `pos`
+    pub fn line_col(&self, pos: usize) -> (usize, usize) {
+        self.ctx().line_col(pos)
+    }
+
+    /// 1-based display column of the byte offset `pos`, tabs expand by `tab_width`
+    /// and with `wide_columns` each char counts its terminal cells. Chars are counted in logical
+    /// order, a line holding right-to-left text may show them elsewhere, see `line_has_bidi`.
+    pub fn column(&self, pos: usize) -> usize {
+        let before = &self.data[..pos];
+        let line_start = before.rfind('\n').map_or(0, |p| p + 1);
+        let tab_width = self.tab_width.max(1);
+        before[line_start..].chars().fold(0, |col, c| match c {
+            '\t' => (col / tab_width + 1) * tab_width,
+            _ if self.wide_columns => col + char_width(c),
+            _ => col + 1,
+        }) + 1
+    }
+
+    /// the tokens starting at a `column` within `lo..=hi`, in order
+    pub fn tokens_in_column_range<'a>(&self, tokens: &'a [LexToken], lo: usize, hi: usize) -> Vec<&'a LexToken> {
+        tokens.iter().filter(|t| (lo..=hi).contains(&self.column(t.start))).collect()
+    }
+
+    /// Look at the leading whitespace of the non blank lines to find how the source is indented.
+    pub fn detect_indent(&self) -> IndentStyle {
+        let (mut tabs, mut spaces, mut width) = (0, 0, 0);
+        let mut mixed = false;
+        for line in self.data.lines().filter(|l| !l.trim().is_empty()) {
+            let indent: Vec<char> = line.chars().take_while(|c| *c == ' ' || *c == '\t').collect();
+            let has_tab = indent.contains(&'\t');
+            let has_space = indent.contains(&' ');
+            mixed |= has_tab && has_space;
+            if indent.first() == Some(&'\t') {
+                tabs += 1;
+            } else if has_space {
+                spaces += 1;
+                let mut a = indent.iter().take_while(|c| **c == ' ').count();
+                let mut b = width;
+                while b != 0 {
+                    (a, b) = (b, a % b);
+                }
+                width = a;
+            }
+        }
+        mixed |= tabs > 0 && spaces > 0;
+        match (tabs, spaces) {
+            (0, 0) => IndentStyle { kind: IndentKind::None, width: 0, mixed },
+            (t, s) if t >= s => IndentStyle { kind: IndentKind::Tabs, width: 1, mixed },
+            _ => IndentStyle { kind: IndentKind::Spaces, width, mixed },
+        }
+    }
+
+    /// the 1-based numbered source lines within `radius` of `lineno`, clamped to the file
+    pub fn lines_around(&self, lineno: usize, radius: usize) -> Vec<(usize, &str)> {
+        let lo = lineno.saturating_sub(radius).max(1);
+        let hi = lineno.saturating_add(radius);
+        self.data.lines().enumerate()
+            .map(|(i, line)| (i + 1, line))
+            .skip(lo - 1)
+            .take_while(|(n, _)| *n <= hi)
+            .collect()
+    }
+
+    /// `err` as `name:line:col: err` with the source line it points at and a caret under the span.
+    /// A line longer than `max_render_width` is cut to a window around the caret with `…`
+    /// marking the cut ends.
+    pub fn render_error(&self, err: &AstError) -> String {
+        let file = self.name.as_ref().map_or(String::new(), |name| format!("{}:", name));
+        let Some((start, end)) = err.span().filter(|(start, _)| self.data.is_char_boundary(*start)) else {
+            return if file.is_empty() { err.to_string() } else { format!("{} {}", file, err) };
+        };
+        let (lineno, col) = self.line_col(start);
+        let line_start = self.data[..start].rfind('\n').map_or(0, |p| p + 1);
+        let line = self.data[line_start..].lines().next().unwrap_or("");
+        let chars: Vec<char> = line.chars().collect();
+        let col = col - 1;
+        let span = self.data.get(start..end.min(line_start + line.len())).map_or(0, |s| s.chars().count());
+        let (mut from, mut to) = (0, chars.len());
+        if let Some(width) = self.max_render_width.filter(|w| *w > 0 && chars.len() > *w) {
+            from = col.saturating_sub(width / 2).min(chars.len() - width);
+            to = from + width;
+        }
+        let mut text: String = chars[from..to].iter().collect();
+        let mut pad = col - from;
+        if from > 0 {
+            text.insert(0, '…');
+            pad += 1;
+        }
+        if to < chars.len() {
+            text.push('…');
+        }
+        let carets = span.clamp(1, (to - col).max(1));
+        format!("{}{}:{}: {}\n{}\n{}{}", file, lineno, col + 1, err, text, " ".repeat(pad), "^".repeat(carets))
+    }
+
+    /// whether line `lineno` holds right-to-left text or bidi controls, so its visual order
+    /// may differ from the columns reported for it
+    pub fn line_has_bidi(&self, lineno: usize) -> bool {
+        self.data.lines().nth(lineno.wrapping_sub(1)).is_some_and(|line| line.chars().any(is_bidi))
+    }
+
+    pub fn get_now_lineno(&self, pos: usize) -> usize {
+        let newlines = |bytes: &[u8]| bytes.iter().filter(|b| **b == b'\n').count();
+        let bytes = self.data.as_bytes();
+        let mut cursor = self.line_cursor.0.lock().unwrap();
+        let (from, line) = *cursor;
+        let line = if from > bytes.len() {
+            // `data` was replaced by a shorter source
+            newlines(&bytes[..pos]) + 1
+        } else if pos >= from {
+            line + newlines(&bytes[from..pos])
+        } else {
+            line - newlines(&bytes[pos..from])
+        };
+        *cursor = (pos, line);
+        line
+    }
+
+    fn new_token(&self, ty: &'static str, start: usize, end: usize) -> LexToken {
+        LexToken {
+            ty,
+            data: self.data.clone(),
+            lineno: self.get_now_lineno(start),
+            start,
+            end,
+            subs: vec![],
+            value: AstAny::Unknow,
+            rule: None,
+            synthetic: false,
+            closed_implicitly: false,
+            canonical: None,
+            captures: vec![],
+            flags: 0,
+            name: None,
+        }
+    }
+
+    /// the heredoc starting at `ori`, its terminator is the word right after the marker
+    fn match_heredoc(&self, ori: usize) -> AstResult<Option<LexToken>> {
+        let data = &self.data[..self.len];
+        for &(marker, ty) in &self.heredocs {
+            if !data[ori..].starts_with(marker) {
+                continue;
+            }
+            let name_start = ori + marker.len();
+            let name_len = data[name_start..].find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(data.len() - name_start);
+            if name_len == 0 {
+                continue;
+            }
+            let name = &data[name_start..name_start + name_len];
+            let unclosed = || AstError::new_no_match_close_error(self.new_token(ty, ori, name_start + name_len));
+            let mut line_start = match data[name_start..].find('\n') {
+                Some(p) => name_start + p + 1,
+                None => return Err(unclosed()),
+            };
+            loop {
+                let line_end = data[line_start..].find('\n').map_or(data.len(), |p| line_start + p);
+                if data[line_start..line_end].trim() == name {
+                    return Ok(Some(self.new_token(ty, ori, line_end)));
+                }
+                if line_end >= data.len() {
+                    return Err(unclosed());
+                }
+                line_start = line_end + 1;
+            }
+        }
+        Ok(None)
+    }
+
+    /// the quoted string starting at `ori`, a backslash escapes the next char
+    fn match_string(&mut self, ori: usize) -> AstResult<Option<LexToken>> {
+        let Some(rule) = self.strings.iter().find(|s| self.data[ori..self.len].starts_with(s.quote)).cloned() else {
+            return Ok(None);
+        };
+        let mut subs = vec![];
+        let mut text_start = ori + rule.quote.len();
+        let mut pos = text_start;
+        loop {
+            let Some(next) = self.get_next_pos(pos) else {
+                return Err(AstError::new_no_match_close_error(self.new_token(rule.ty, ori, ori + rule.quote.len())));
+            };
+            let rest = &self.data[pos..self.len];
+            if rest.starts_with('\\') {
+                pos = self.get_next_pos(next).unwrap_or(next);
+            } else if rest.starts_with(rule.quote) {
+                let end = pos + rule.quote.len();
+                if !rule.interpolate {
+                    return Ok(Some(self.new_text_token(rule.ty, ori, end, text_start, pos)?));
+                }
+                if text_start < pos {
+                    subs.push(self.new_text_token("text", text_start, pos, text_start, pos)?);
+                }
+                let mut token = self.new_token(rule.ty, ori, end);
+                token.subs = subs;
+                return Ok(Some(token));
+            } else if rule.interpolate && rest.starts_with("${") {
+                if text_start < pos {
+                    subs.push(self.new_text_token("text", text_start, pos, text_start, pos)?);
+                }
+                let group = self.lex_interpolation(pos)?;
+                pos = group.subs.last().map_or(pos + 2, |t| t.end);
+                text_start = pos;
+                subs.push(group);
+            } else {
+                pos = next;
+            }
+        }
+    }
+
+    /// lex the expression of `${...}` up to its closing `}`, which ends the group's `subs`
+    fn lex_interpolation(&mut self, start: usize) -> AstResult<LexToken> {
+        let mut group = self.new_token("interp", start, start + 2);
+        let old_pos = self.pos;
+        self.pos = start + 2;
+        let mut depth = 0;
+        loop {
+            let Some(token) = self.get_token()? else {
+                return Err(AstError::new_no_match_close_error(group));
+            };
+            if token.ty == "lit" {
+                match token.get_value() {
+                    "{" => depth += 1,
+                    "}" if depth == 0 => {
+                        group.subs.push(token);
+                        break;
                     }
-                    self.pos = p.end();
-                    return Some(LexToken {
-                        ty: re.ty,
-                        data: self.data.clone(),
-                        lineno: self.get_now_lineno(ori),
-                        start: p.start(),
-                        end: p.end(),
-                        subs: vec![],
-                        value: AstAny::Unknow,
-                    })
+                    "}" => depth -= 1,
+                    _ => {}
+                }
+            }
+            group.subs.push(token);
+        }
+        self.pos = old_pos;
+        Ok(group)
+    }
+
+    /// whether a directive may start at `ori`, only the text since the last call is looked at
+    /// so a line is not rescanned for each of its chars
+    fn at_line_start(&mut self, ori: usize) -> bool {
+        let (mut from, mut at_start) = self.line_state;
+        if ori < from {
+            // moved back, as `tokenize` does, start again from the line
+            from = self.data[..ori].rfind('\n').map_or(0, |p| p + 1);
+            at_start = true;
+        }
+        let mut gap = &self.data[from..ori];
+        if let Some(p) = gap.rfind('\n') {
+            gap = &gap[p + 1..];
+            at_start = true;
+        }
+        at_start = at_start && gap.chars().all(|c| self.ignore.contains(c));
+        self.line_state = (ori, at_start);
+        at_start
+    }
+
+    /// handle the directive line at `ori`, returning where the line ends
+    fn match_directive(&mut self, ori: usize) -> AstResult<Option<usize>> {
+        let Some(prefix) = self.directive_prefix else {
+            return Ok(None);
+        };
+        let data = self.data.clone();
+        let line_end = data[ori..self.len].find('\n').map_or(self.len, |p| ori + p);
+        let Some(body) = data[ori..line_end].strip_prefix(prefix) else {
+            return Ok(None);
+        };
+        let token = self.new_token("directive", ori, line_end);
+        let mut words = body.split_whitespace();
+        match (words.next(), words.next()) {
+            (Some("if" | "ifdef"), Some(name)) => {
+                let active = self.defines.contains(name);
+                self.directives.push((token, active));
+            }
+            (Some("ifndef"), Some(name)) => {
+                let active = !self.defines.contains(name);
+                self.directives.push((token, active));
+            }
+            (Some("else"), _) => match self.directives.last_mut() {
+                Some(last) => last.1 = !last.1,
+                None => return Err(AstError::new_unexpected_token_error(token)),
+            },
+            (Some("endif"), _) => {
+                if self.directives.pop().is_none() {
+                    return Err(AstError::new_unexpected_token_error(token));
+                }
+            }
+            _ => return Ok(None),
+        }
+        Ok(Some(line_end))
+    }
+
+    /// the rule matching at `ori` under `match_policy`, longest match ties go to the earlier rule
+    fn match_regex(&mut self, ori: usize) -> Option<(usize, usize)> {
+        let mut best: Option<(usize, usize)> = None;
+        // one byte past the window tells a match that fits from one that was cut short
+        let mut cut = self.match_window.map_or(self.len, |w| ori.saturating_add(w).saturating_add(1).min(self.len));
+        while !self.data.is_char_boundary(cut) {
+            cut += 1;
+        }
+        let key = |idx: usize| (std::cmp::Reverse(self.res[idx].priority), idx);
+        if self.rule_order.len() != self.res.len() || !self.rule_order.windows(2).all(|w| key(w[0]) < key(w[1])) {
+            self.rule_order = (0..self.res.len()).collect();
+            self.rule_order.sort_by_key(|idx| std::cmp::Reverse(self.res[*idx].priority));
+        }
+        for i in 0..self.rule_order.len() {
+            let idx = self.rule_order[i];
+            let found = match self.match_window {
+                Some(_) => self.res[idx].re.find_at(&self.data[..cut], ori).map(|m| (m.start(), m.end())),
+                None => self.probe_regex(idx, ori),
+            };
+            // an empty match would hand out empty tokens without ever moving on
+            let Some((_, end)) = found.filter(|(start, end)| *start == ori && *end != ori) else {
+                continue;
+            };
+            if self.match_window.is_some_and(|w| end - ori > w) {
+                self.overruns.push((idx, ori));
+                continue;
+            }
+            if best.is_none_or(|(_, best_end)| end > best_end) {
+                best = Some((idx, end));
+            }
+            if self.match_policy == MatchPolicy::FirstMatch {
+                break;
+            }
+        }
+        best
+    }
+
+    pub fn get_token(&mut self) -> AstResult<Option<LexToken>> {
+        if self.literal_set.src != self.literals {
+            self.literal_set = LiteralSet::new(&self.literals);
+        }
+        let data = self.data.clone();
+        let mut ori = self.pos;
+        loop {
+            let pos = self.get_next_pos(ori);
+            let Some(pos) = pos else {
+                if let Some((token, _)) = self.directives.pop() {
+                    return Err(AstError::new_no_match_close_error(token));
+                }
+                return Ok(None);
+            };
+            if self.directive_prefix.is_some() && self.at_line_start(ori) {
+                if let Some(end) = self.match_directive(ori)? {
+                    self.pos = end;
+                    ori = end;
+                    continue;
+                }
+            }
+            if !self.directives.iter().all(|d| d.1) {
+                let end = data[ori..self.len].find('\n').map_or(self.len, |p| ori + p + 1);
+                self.pos = end;
+                ori = end;
+                continue;
+            }
+            let val = data.get(ori .. pos).unwrap();
+            if self.trivia && (self.is_ignored(val) || (!self.emit_newline && (val == "\n" || val == "\r"))) {
+                let (mut end, mut count) = (pos, 1);
+                while data[end..self.len].starts_with(val) {
+                    end += val.len();
+                    count += 1;
+                }
+                self.pos = end;
+                self.record(LexEvent::Other { ty: "trivia", start: ori, end });
+                let mut token = self.new_token("trivia", ori, end);
+                token.value = AstAny::Usize(count);
+                return Ok(Some(token));
+            }
+            if self.emit_ws && self.is_ignored(val) {
+                let mut end = pos;
+                while let Some(next) = self.get_next_pos(end).filter(|p| self.is_ignored(&data[end..*p])) {
+                    end = next;
+                }
+                self.pos = end;
+                self.record(LexEvent::Other { ty: "ws", start: ori, end });
+                return Ok(Some(self.new_token("ws", ori, end)));
+            }
+            if self.is_ignored(val) {
+                self.record(LexEvent::SkipIgnore { pos: ori });
+                self.pos = pos;
+                ori = pos;
+                continue;
+            }
+
+            if self.emit_newline && val == "\n" {
+                let mut end = pos;
+                if self.collapse_newline {
+                    let mut next = pos;
+                    while let Some(p) = self.get_next_pos(next) {
+                        let val = &self.data[next..p];
+                        if val == "\n" {
+                            end = p;
+                        } else if !self.is_ignored(val) {
+                            break;
+                        }
+                        next = p;
+                    }
+                }
+                self.pos = end;
+                self.record(LexEvent::Other { ty: "newline", start: ori, end });
+                return Ok(Some(self.new_token("newline", ori, end)));
+            }
+
+            if let Some((end, emit)) = self.match_comment(ori)? {
+                self.pos = end;
+                self.record(LexEvent::Other { ty: "comment", start: ori, end });
+                if emit {
+                    return Ok(Some(self.new_token("comment", ori, end)));
+                }
+                ori = end;
+                continue;
+            }
+
+            if let Some(token) = self.match_heredoc(ori)? {
+                self.pos = token.end;
+                self.record(LexEvent::Other { ty: token.ty, start: ori, end: token.end });
+                return Ok(Some(token));
+            }
+
+            if let Some(token) = self.match_string(ori)? {
+                self.pos = token.end;
+                self.record(LexEvent::Other { ty: token.ty, start: ori, end: token.end });
+                return Ok(Some(token));
+            }
+
+            let literal = self.match_literal(ori, pos);
+            let regex = self.match_regex(ori).map(|(rule, end)| (rule, self.extend_ident(rule, end)));
+            let take_literal = match (literal, regex) {
+                (Some(lit), Some((_, end))) => lit > end || (lit == end && self.literal_priority),
+                (lit, _) => lit.is_some(),
+            };
+            if let (true, Some(end)) = (take_literal, literal) {
+                self.pos = end;
+                self.record(LexEvent::MatchLiteral { start: ori, end });
+                return Ok(Some(self.new_token("lit", ori, end)));
+            }
+
+            if let Some((rule, end)) = regex {
+                self.pos = end;
+                self.record(LexEvent::MatchRegex { rule, start: ori, end });
+                let ty = self.keyword_type(self.res[rule].ty, &data[ori..end]);
+                let mut token = self.new_token(ty, ori, end);
+                if ty == "id" && self.max_identifier_len.is_some_and(|max| data[ori..end].chars().count() > max) {
+                    return Err(AstError::new_ident_too_long_error(token));
+                }
+                token.rule = Some(rule);
+                if ty == self.res[rule].ty {
+                    token.name = self.res[rule].name.clone();
+                }
+                if self.res[rule].capturing {
+                    if let Some(caps) = self.res[rule].re.captures_at(&data[..self.len], ori) {
+                        token.captures = caps.iter().skip(1).map(|m| m.map_or((ori, ori), |m| (m.start(), m.end()))).collect();
+                    }
+                }
+                return Ok(Some(token));
+            }
+            if val != "\n" && val != "\r" {
+                self.record(LexEvent::Unknown { pos: ori });
+                let ch = val.chars().next().unwrap();
+                if let Some(mut token) = self.handler.on_unknown(ch, ori)? {
+                    token.data = self.data.clone();
+                    token.lineno = self.get_now_lineno(token.start);
+                    self.pos = token.end.max(pos);
+                    return Ok(Some(token));
+                }
+            } else {
+                self.record(LexEvent::SkipIgnore { pos: ori });
+            }
+            self.pos = pos;
+            ori = pos;
+        }
+    }
+
+    fn record(&mut self, event: LexEvent) {
+        if self.trace {
+            self.events.push(event);
+        }
+    }
+
+    /// the events `get_token` recorded with `trace` on since the last call, oldest first
+    pub fn take_trace(&mut self) -> Vec<LexEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Read the next tokens when they match `pats` one for one, otherwise read nothing and
+    /// leave the position, open directives, trace and `overruns` as they were. Calls already
+    /// made to the handler are not undone.
+    pub fn match_seq(&mut self, pats: &[Pat]) -> Option<Vec<LexToken>> {
+        let saved = (self.pos, self.directives.clone(), self.line_state, self.events.len(), self.overruns.len());
+        let mut tokens = Vec::with_capacity(pats.len());
+        for pat in pats {
+            match self.get_token() {
+                Ok(Some(token)) if pat.matches(&token) => tokens.push(token),
+                _ => {
+                    let (pos, directives, line_state, events, overruns) = saved;
+                    (self.pos, self.directives, self.line_state) = (pos, directives, line_state);
+                    self.events.truncate(events);
+                    self.overruns.truncate(overruns);
+                    return None;
                 }
             }
-            println!("now data = {:?}", self.data.get(ori .. pos.unwrap()));
-            println!("ori = {:?} pos = {:?}", ori, pos);
-            ori = pos.unwrap();
         }
+        Some(tokens)
+    }
+
+    /// Discard tokens up to and including the first whose `type_name` is in `types`, for panic mode
+    /// recovery. Input that fails to lex is stepped over a char at a time, `None` at EOF.
+    pub fn skip_to_any(&mut self, types: &[&'static str]) -> Option<LexToken> {
+        loop {
+            match self.get_token() {
+                Ok(Some(token)) if types.contains(&token.type_name()) => return Some(token),
+                Ok(Some(_)) => {}
+                Ok(None) => return None,
+                Err(_) => self.pos = self.get_next_pos(self.pos)?,
+            }
+        }
+    }
+
+    /// Push each remaining token into `sink` as it is read, the first error from either stops it.
+    pub fn lex_into(&mut self, mut sink: impl FnMut(LexToken) -> AstResult<()>) -> AstResult<()> {
+        while let Some(token) = self.get_token()? {
+            sink(token)?;
+        }
+        Ok(())
+    }
+
+    /// Iterate the remaining tokens lazily, the lexer advances as they are taken.
+    pub fn tokens(&mut self) -> Tokens<'_, H> {
+        Tokens { lexer: self, done: false }
+    }
+
+    /// `tokens` paired with their 1-based line and `column`.
+    pub fn tokens_with_pos(&mut self) -> TokenPositions<'_, H> {
+        TokenPositions(self.tokens())
+    }
+
+    /// Lex the whole source into a flat token list, the lexer position is left as is.
+    pub fn tokenize(&mut self) -> AstResult<Vec<LexToken>> {
+        self.tokenize_range(0, self.data.len())
+    }
+
+    /// Lex only `data[start..end]`, the tokens keep their absolute offsets into `data`.
+    /// The lexer position is restored afterwards so a full scan is not disturbed.
+    pub fn tokenize_range(&mut self, start: usize, end: usize) -> AstResult<Vec<LexToken>> {
+        if start > end || end > self.data.len() || !self.data.is_char_boundary(start) || !self.data.is_char_boundary(end) {
+            return Err(AstError::new_out_of_range_error(start, end));
+        }
+        let (old_pos, old_len) = (self.pos, self.len);
+        self.pos = start;
+        self.len = end;
+        let mut tokens = Vec::with_capacity(self.capacity_hint);
+        let result = loop {
+            match self.get_token() {
+                Ok(Some(token)) if self.max_tokens.is_some_and(|max| tokens.len() >= max) => {
+                    break Err(AstError::new_too_many_tokens_error(token.start));
+                }
+                Ok(Some(token)) => tokens.push(token),
+                Ok(None) => break Ok(tokens),
+                Err(err) => break Err(err),
+            }
+        };
+        self.pos = old_pos;
+        self.len = old_len;
+        result
+    }
+
+    /// The tokens ending at or before `pos`, nearest first, for looking back from a position.
+    /// The source is lexed from the start, a token running across `pos` is not included
+    /// and the lexer position is restored afterwards.
+    pub fn tokens_before(&mut self, pos: usize) -> AstResult<Vec<LexToken>> {
+        let (old_pos, old_len) = (self.pos, self.len);
+        self.pos = 0;
+        self.len = self.data.len();
+        let mut tokens = vec![];
+        let result = loop {
+            match self.get_token() {
+                Ok(Some(token)) if token.end <= pos => tokens.push(token),
+                Ok(_) => break Ok(()),
+                Err(err) => break Err(err),
+            }
+        };
+        self.pos = old_pos;
+        self.len = old_len;
+        result?;
+        tokens.reverse();
+        Ok(tokens)
+    }
+
+    /// The verbatim source from the start of `start` to the end of `end`, whitespace included.
+    pub fn slice_between(&self, start: &LexToken, end: &LexToken) -> AstResult<&str> {
+        if start.start > end.end || end.end > self.data.len() {
+            return Err(AstError::new_out_of_range_error(start.start, end.end));
+        }
+        self.data.get(start.start..end.end).ok_or_else(|| AstError::new_out_of_range_error(start.start, end.end))
+    }
+
+    /// Scan the rest of the input and return how many tokens it holds, the lexer is left at the end.
+    pub fn count_tokens(&mut self) -> AstResult<usize> {
+        let mut count = 0;
+        while self.get_token()?.is_some() {
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Find runs of touching single char literals that spell a multi char operator from `precs`.
+    /// Returns the start of each run and the operator it would have been merged into.
+    pub fn report_suspicious_splits(&self, tokens: &[LexToken]) -> Vec<(usize, String)> {
+        let ops: Vec<&str> = self.precs.iter()
+            .filter(|p| p.ty == "lit")
+            .flat_map(|p| p.precs.iter().copied())
+            .filter(|op| op.chars().count() > 1)
+            .collect();
+        let mut flat = vec![];
+        let mut stack: Vec<&LexToken> = tokens.iter().rev().collect();
+        while let Some(token) = stack.pop() {
+            flat.push(token);
+            stack.extend(token.subs.iter().rev());
+        }
+        let mut found = vec![];
+        let mut i = 0;
+        while i < flat.len() {
+            let mut text = String::new();
+            let mut best = None;
+            for (j, token) in flat.iter().enumerate().skip(i) {
+                let value = token.get_value();
+                if token.ty != "lit" || value.chars().count() != 1 || (j > i && flat[j - 1].end != token.start) {
+                    break;
+                }
+                text.push_str(value);
+                if !ops.iter().any(|op| op.starts_with(text.as_str())) {
+                    break;
+                }
+                if j > i && ops.contains(&text.as_str()) {
+                    best = Some((j, text.clone()));
+                }
+            }
+            match best {
+                Some((j, op)) => {
+                    found.push((flat[i].start, op));
+                    i = j + 1;
+                }
+                None => i += 1,
+            }
+        }
+        found
     }
 
     pub fn read_token(handler: &mut H, token: &mut LexToken) -> AstResult<()> {
@@ -247,69 +2155,194 @@ impl<H> Lexer<H> where H: Handler {
         Ok(())
     }
 
-    pub fn parser_token(&mut self) -> AstResult<()> {
-        self.tokenstack = vec![];
-        while let Some(mut token) = self.get_token() {
-            println!("token = {:?}", self.hash_matchs);
-
-            println!("token = {:?} 11 = {} match = {}", token, token.ty == "id", token.get_value());
+    /// Read the rest of the input as a flat token stream, `transformers` applied.
+    pub fn lex(&mut self) -> AstResult<Vec<LexToken>> {
+        let mut tokens = Vec::with_capacity(self.capacity_hint);
+        while let Some(raw) = self.get_token()? {
+            for token in self.transform(raw) {
+                if self.max_tokens.is_some_and(|max| tokens.len() >= max) {
+                    return Err(AstError::new_too_many_tokens_error(token.start));
+                }
+                tokens.push(token);
+            }
+        }
+        Ok(tokens)
+    }
 
-            // println!("token = {:?} match = {}", token, token.ty == "id" && self.hash_matchs.contains_key(token.get_value()));
+    /// push `tokens` onto `stack`, the groups still open at the end are its last
+    /// `wait_token.len()` entries
+    fn nest(&mut self, stack: &mut Vec<LexToken>, tokens: Vec<LexToken>) {
+        for token in tokens {
+            if token.ty == "comment" {
+                continue;
+            }
             if self.hash_matchs.contains_key(&(token.ty, token.get_value())) {
                 self.wait_token.push(token.clone_base_token());
-            } else {
-                if self.wait_token.len() > 0 {
-                    let same_type = {
-                        let last = self.wait_token.last().unwrap();
-                        if last.ty != token.ty {
-                            false
-                        } else {
-                            self.hash_matchs.get(&(last.ty, last.get_value())) == Some(&token.get_value())
-                        }
-                    };
-                    self.tokenstack.last_mut().unwrap().subs.push(token);
-                    if same_type {
-                        self.wait_token.pop();
-                        if self.wait_token.len() > 0 {
-                            let last_group = self.tokenstack.pop().unwrap();
-                            self.tokenstack.last_mut().unwrap().subs.push(last_group);
-                        }
+            } else if !self.wait_token.is_empty() {
+                let same_type = self.is_closer_of(self.wait_token.last().unwrap(), &token);
+                stack.last_mut().unwrap().subs.push(token);
+                if same_type {
+                    self.wait_token.pop();
+                    if !self.wait_token.is_empty() {
+                        let last_group = stack.pop().unwrap();
+                        stack.last_mut().unwrap().subs.push(last_group);
                     }
-                    continue;
                 }
+                continue;
             }
-            
-            self.tokenstack.push(token);
+            stack.push(token);
         }
+    }
+
+    /// Nest a flat stream by `hash_matchs`, each group is its opener with the inner tokens
+    /// and the closer as the last entry in `subs`. `"comment"` tokens are dropped.
+    pub fn group(&mut self, tokens: Vec<LexToken>) -> AstResult<Vec<LexToken>> {
+        let mut stack: Vec<LexToken> = Vec::with_capacity(self.capacity_hint);
+        self.wait_token.clear();
+        self.diagnostics.clear();
+        self.nest(&mut stack, tokens);
 
-        if self.wait_token.len() > 0 {
-            println!("error!!!!!!!!!!!!!! = {:?}", self.wait_token);
+        if !self.wait_token.is_empty() && !self.recover_brackets {
             return Err(AstError::new_no_match_close_error(self.wait_token.pop().unwrap()));
         }
-        println!("self.tokenstack = {:?}", self.tokenstack);
+        while let Some(opener) = self.wait_token.pop() {
+            let mut closer = self.new_token(opener.ty, self.len, self.len);
+            closer.synthetic = true;
+            let group = stack.last_mut().unwrap();
+            group.closed_implicitly = true;
+            group.subs.push(closer);
+            if !self.wait_token.is_empty() {
+                let last_group = stack.pop().unwrap();
+                stack.last_mut().unwrap().subs.push(last_group);
+            }
+            self.diagnostics.push(opener);
+        }
+        Ok(stack)
+    }
+
+    /// Let `f` adjust each token of `tokenstack` seeing its neighbors, siblings of one group are
+    /// neighbors to each other and the walk goes in source order so `prev` is already rewritten.
+    pub fn contextual_rewrite(&mut self, mut f: impl FnMut(Option<&LexToken>, &mut LexToken, Option<&LexToken>)) {
+        // the sibling lists being walked with the index of the next token, a token's own subs
+        // are taken out while they are walked and put back once done
+        let mut stack = vec![(std::mem::take(&mut self.tokenstack), 0)];
+        loop {
+            let (tokens, idx) = stack.last_mut().unwrap();
+            if *idx < tokens.len() {
+                let (before, rest) = tokens.split_at_mut(*idx);
+                let (cur, after) = rest.split_first_mut().unwrap();
+                f(before.last(), cur, after.first());
+                let subs = std::mem::take(&mut cur.subs);
+                *idx += 1;
+                stack.push((subs, 0));
+                continue;
+            }
+            let (tokens, _) = stack.pop().unwrap();
+            match stack.last_mut() {
+                Some((parent, idx)) => parent[*idx - 1].subs = tokens,
+                None => {
+                    self.tokenstack = tokens;
+                    return;
+                }
+            }
+        }
+    }
+
+    pub fn parser_token(&mut self) -> AstResult<()> {
+        self.tokenstack = vec![];
+        let mut tokens = Vec::with_capacity(self.capacity_hint);
+        for token in self.lex()? {
+            tokens.extend(self.handler.on_token(token)?);
+        }
+        self.tokenstack = self.group(tokens)?;
         Ok(())
     }
 
+    /// Append `line` to `data`, a newline added when it has none, and lex just that part.
+    /// Returns the new top-level tokens grouped, a group left open waits in `wait_token`
+    /// and comes back whole from the call that closes it. The tokens point into the text of the
+    /// line they were fed with, so their spans count from its start while `lineno` counts every
+    /// line fed, and `data` is not shared with them and is grown in place.
+    pub fn feed_line(&mut self, line: &str) -> AstResult<Vec<LexToken>> {
+        let start = self.data.len();
+        let data = Arc::make_mut(&mut self.data);
+        data.push_str(line);
+        if !line.ends_with('\n') {
+            data.push('\n');
+        }
+        self.is_ascii = self.is_ascii && line.is_ascii();
+        self.pos = start;
+        self.len = self.data.len();
+        let mut tokens = self.lex()?;
+        let chunk = Arc::new(self.data[start..].to_string());
+        let mut stack: Vec<&mut LexToken> = tokens.iter_mut().collect();
+        while let Some(token) = stack.pop() {
+            token.data = chunk.clone();
+            (token.start, token.end) = (token.start - start, token.end - start);
+            for (from, to) in token.captures.iter_mut() {
+                (*from, *to) = (*from - start, *to - start);
+            }
+            stack.extend(token.subs.iter_mut());
+        }
+        let mut stack = std::mem::take(&mut self.fed_groups);
+        self.nest(&mut stack, tokens);
+        self.fed_groups = stack.split_off(stack.len() - self.wait_token.len());
+        Ok(stack)
+    }
+
     pub fn iter_read_token(&mut self, mut token: LexToken) -> AstResult<()> {
-        println!("read token = {:?}", token);
         // token.subs
         token.value = self.handler.on_read(&mut token)?;
         Ok(())
     }
 
     pub fn eval(&mut self) -> AstResult<AstAny> {
-        if self.tokenstack.len() == 0 {
+        if self.tokenstack.is_empty() {
             self.parser_token()?;
         }
 
-        let mut temp: Vec<_> = self.tokenstack.drain(..).collect();
-        for token in temp.drain(..) {
-            self.iter_read_token(token);
-            // token.value = self.handler.on_read(&mut token)?;
-            // Self::read_token(&mut self.handler, token)?;
+        let temp: Vec<_> = self.tokenstack.drain(..).collect();
+        if temp.is_empty() {
+            return Ok(AstAny::Unknow);
+        }
+        let mut expr = self.parse_expr(&temp)?;
+        self.eval_expr(&mut expr)
+    }
+
+    /// `eval` allowed at most `max_steps` handler calls, one per tree node, so untrusted input
+    /// cannot make it work unbounded.
+    pub fn eval_budget(&mut self, max_steps: usize) -> AstResult<AstAny> {
+        self.budget = Some(max_steps);
+        let result = self.eval();
+        self.budget = None;
+        result
+    }
+
+    /// Evaluate each top-level statement split on `terminator`, empty statements are skipped.
+    pub fn eval_all(&mut self) -> AstResult<Vec<AstAny>> {
+        if self.tokenstack.is_empty() {
+            self.parser_token()?;
         }
 
-        Ok(AstAny::Unknow)
+        let temp: Vec<_> = self.tokenstack.drain(..).collect();
+        let terminator = self.terminator;
+        let mut values = vec![];
+        for stmt in temp.split(|t| t.ty == "lit" && t.get_value() == terminator) {
+            if stmt.is_empty() {
+                continue;
+            }
+            let mut expr = self.parse_expr(stmt)?;
+            values.push(self.eval_expr(&mut expr)?);
+        }
+        Ok(values)
+    }
+}
 
+impl Lexer<DefaultHandler> {
+    /// Check that `src` lexes with `rules` and every group closes, no handler is involved.
+    pub fn validate_source(src: &str, rules: Vec<LexRegex>) -> AstResult<()> {
+        let mut lexer = Lexer::new(src.to_string(), DefaultHandler);
+        lexer.set_regexes(rules);
+        lexer.parser_token()
     }
 }
@@ -1,11 +1,26 @@
 
 mod loc;
 mod lexer;
+mod parser;
 mod any;
 mod handler;
 mod result;
+mod preprocess;
+mod tree;
+mod escape;
+mod codec;
+mod diff;
+mod expr;
+mod intern;
 
-pub use lexer::{Lexer, LexToken, LexPrec};
-pub use any::AstAny;
-pub use handler::{Handler, DefaultHandler};
-pub use result::{AstResult, AstError};
\ No newline at end of file
+pub use lexer::{Lexer, LexToken, LexPrec, LexRegex, LexString, LexComment, LexMixfix, LexFixity, LexTransformer, LexDecoder, LexIgnore, unicode_whitespace, MatchPolicy, ColonRole, Pat, LexEvent, RuleWarning, Tokens, Statements, TokenPositions, IndentKind, IndentStyle, assert_token_shape};
+pub use any::{AstAny, AstCustom, IntOverflow};
+pub use handler::{Handler, DefaultHandler, LexerCtx};
+pub use result::{AstResult, AstError};
+pub use loc::SourceMap;
+pub use preprocess::Preprocessor;
+pub use tree::{TokenTree, ParseNode};
+pub use diff::{TokenEdit, diff_tokens, type_histogram};
+pub use expr::Expr;
+pub use intern::{TypeInterner, TypeName};
+pub use escape::{EscapeDecoder, DefaultEscapeDecoder};
\ No newline at end of file
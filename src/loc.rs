@@ -1,11 +1,13 @@
 use std::sync::Arc;
 
+#[allow(dead_code)]
 #[derive(Clone, Eq, PartialEq, Hash)]
 pub struct SourceRange {
     pub begin: BareSourceLocation,
     pub end: BareSourceLocation,
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub struct BareSourceLocation {
     pub offset: usize,
@@ -15,6 +17,7 @@ pub struct BareSourceLocation {
     pub tok_len: usize,
 }
 
+#[allow(dead_code)]
 #[derive(Clone, Eq, PartialEq, Hash, Debug)]
 pub struct Production {
     pub file: Arc<str>,
@@ -22,3 +25,33 @@ pub struct Production {
     pub stack: u32,
 }
 
+
+/// Maps offsets of a preprocessed source back to the original text.
+#[derive(Clone, Eq, PartialEq, Hash, Debug, Default)]
+pub struct SourceMap {
+    anchors: Vec<(usize, usize)>,
+}
+
+impl SourceMap {
+    pub fn new() -> SourceMap {
+        SourceMap::default()
+    }
+
+    /// from `processed` on the text maps to `original` on, add in increasing order
+    pub fn add_mapping(&mut self, processed: usize, original: usize) {
+        self.anchors.push((processed, original));
+    }
+
+    pub fn to_original(&self, offset: usize) -> usize {
+        let idx = self.anchors.partition_point(|a| a.0 <= offset);
+        if idx == 0 {
+            return offset;
+        }
+        let (processed, original) = self.anchors[idx - 1];
+        let mapped = original + (offset - processed);
+        match self.anchors.get(idx) {
+            Some(next) => mapped.min(next.1),
+            None => mapped,
+        }
+    }
+}
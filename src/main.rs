@@ -17,6 +17,23 @@ impl Handler for CalcHandler {
             }
         }
     }
+
+    fn on_unary(&mut self, op: &LexToken, value: AstAny) -> AstResult<AstAny> {
+        match (op.get_value(), value) {
+            ("-", AstAny::I64(v)) => Ok(AstAny::I64(-v)),
+            _ => Ok(AstAny::Unsport),
+        }
+    }
+
+    fn on_binary(&mut self, op: &LexToken, lhs: AstAny, rhs: AstAny) -> AstResult<AstAny> {
+        match (op.get_value(), lhs, rhs) {
+            ("+", AstAny::I64(a), AstAny::I64(b)) => Ok(AstAny::I64(a + b)),
+            ("-", AstAny::I64(a), AstAny::I64(b)) => Ok(AstAny::I64(a - b)),
+            ("*", AstAny::I64(a), AstAny::I64(b)) => Ok(AstAny::I64(a * b)),
+            ("/", AstAny::I64(a), AstAny::I64(b)) if b != 0 => Ok(AstAny::I64(a / b)),
+            _ => Ok(AstAny::Unsport),
+        }
+    }
 }
 fn main() {
 // t_STRING = r'\"([^\\\n]|(\\.))*?\"'
@@ -31,7 +48,7 @@ fn main() {
 
 // # String literal
 // t_STRING = r'\"([^\\\n]|(\\.))*?\"'
-    let value = "right11=2211;\n
+    let _value = "right11=2211;\n
     
     aaass=222uiii".to_string();
     let value = "1 + 2*-3".to_string();
@@ -0,0 +1,524 @@
+use std::sync::Arc;
+
+use crate::{Handler, Lexer, LexToken, LexerCtx, AstAny, AstResult, AstError, ColonRole};
+
+impl<H> Lexer<H> where H: Handler {
+    /// Parse the grouped tokens into one expression tree by precedence climbing.
+    /// An operator node is the operator token with its operands in `subs`,
+    /// a bracket group is replaced by the expression it holds.
+    pub fn parse_expr(&self, tokens: &[LexToken]) -> AstResult<LexToken> {
+        self.parse_tokens(tokens, 0)
+    }
+
+    fn parse_tokens(&self, tokens: &[LexToken], depth: usize) -> AstResult<LexToken> {
+        let mut pos = 0;
+        let expr = self.parse_prec(tokens, &mut pos, 0, depth)?;
+        if let Some(token) = tokens.get(pos) {
+            return Err(AstError::new_unexpected_token_error(token.clone_base_token()));
+        }
+        Ok(expr)
+    }
+
+    fn parse_prec(&self, tokens: &[LexToken], pos: &mut usize, min_prec: i32, depth: usize) -> AstResult<LexToken> {
+        if depth > self.max_depth {
+            return Err(match tokens.get(*pos) {
+                Some(token) => AstError::new_too_deep_error(token.clone_base_token()),
+                None => AstError::new_unexpected_eof_error(),
+            });
+        }
+        let mut lhs = self.parse_unary(tokens, pos, depth)?;
+        while let Some(token) = tokens.get(*pos) {
+            if (token.ty, token.get_value()) == self.call_group && token.is_open_bracket(self) {
+                *pos += 1;
+                let mut node = token.clone_base_token();
+                node.subs = vec![lhs];
+                node.subs.extend(self.parse_items(self.group_inner(token), depth)?);
+                lhs = node;
+                continue;
+            }
+            let fixity = self.fixity(token);
+            let level = self.prec_hash.get(&self.op_key(token)).copied();
+            if fixity.postfix && !(fixity.infix && tokens.get(*pos + 1).is_some_and(|next| self.starts_operand(next))) {
+                match level.map(|l| l.1).or_else(|| self.prefix_hash.get(&self.op_key(token)).copied()) {
+                    Some(prec) if prec >= min_prec => {}
+                    _ => break,
+                }
+                *pos += 1;
+                let mut node = token.clone_base_token();
+                node.canonical = self.aliases.get(token.get_value()).copied();
+                node.subs = vec![lhs];
+                lhs = node;
+                continue;
+            }
+            let annotation = self.colon_role == ColonRole::Annotation
+                && (token.ty, token.get_value()) == (self.ternary.0, self.ternary.2);
+            let level = if annotation {
+                let top = self.prec_hash.values().map(|l| l.1).chain(self.prefix_hash.values().copied()).max();
+                Some((true, top.unwrap_or(0) + 1))
+            } else {
+                level
+            };
+            let (left, prec) = match level {
+                Some((left, prec)) if (fixity.infix || annotation) && prec >= min_prec => (left, prec),
+                _ => break,
+            };
+            *pos += 1;
+            let mut node = token.clone_base_token();
+            node.canonical = self.aliases.get(token.get_value()).copied();
+            if self.colon_role == ColonRole::Ternary && self.op_key(token) == (self.ternary.0, self.ternary.1) {
+                let then = self.parse_prec(tokens, pos, 0, depth + 1)?;
+                match tokens.get(*pos) {
+                    Some(colon) if (colon.ty, colon.get_value()) == (self.ternary.0, self.ternary.2) => *pos += 1,
+                    _ => return Err(AstError::new_no_match_close_error(node)),
+                }
+                let other = self.parse_prec(tokens, pos, prec, depth + 1)?;
+                node.subs = vec![lhs, then, other];
+                lhs = node;
+                continue;
+            }
+            let rhs = self.parse_prec(tokens, pos, if left { prec + 1 } else { prec }, depth + 1)?;
+            node.subs = vec![lhs, rhs];
+            lhs = node;
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&self, tokens: &[LexToken], pos: &mut usize, depth: usize) -> AstResult<LexToken> {
+        let Some(token) = tokens.get(*pos) else {
+            return Err(AstError::new_unexpected_eof_error());
+        };
+        *pos += 1;
+        if let Some(mixfix) = self.mixfix.iter().find(|m| m.markers.first() == Some(&token.get_value())) {
+            let mut node = token.clone_base_token();
+            for (idx, marker) in mixfix.markers.iter().enumerate() {
+                if idx > 0 {
+                    match tokens.get(*pos) {
+                        Some(next) if next.get_value() == *marker => *pos += 1,
+                        _ => return Err(AstError::new_no_match_close_error(node)),
+                    }
+                }
+                let min_prec = if idx + 1 == mixfix.markers.len() { mixfix.prec } else { 0 };
+                node.subs.push(self.parse_prec(tokens, pos, min_prec, depth + 1)?);
+            }
+            return Ok(node);
+        }
+        let fixity = self.fixity(token);
+        if let (true, Some(&prec)) = (fixity.prefix, self.prefix_hash.get(&self.op_key(token))) {
+            let mut node = token.clone_base_token();
+            node.canonical = self.aliases.get(token.get_value()).copied();
+            node.subs = vec![self.parse_prec(tokens, pos, prec, depth + 1)?];
+            return Ok(node);
+        }
+        // an infix operator where an operand belongs, like the second of `a + * b`
+        if fixity.infix && self.prec_hash.contains_key(&self.op_key(token)) {
+            return Err(AstError::new_unexpected_token_error(token.clone_base_token()));
+        }
+        if token.is_open_bracket(self) {
+            return self.parse_group(token, depth);
+        }
+        if token.is_close_bracket(self) {
+            return Err(AstError::new_unexpected_token_error(token.clone_base_token()));
+        }
+        Ok(token.clone_base_token())
+    }
+
+    /// whether `token` can begin an operand, so an operator before it is not postfix
+    fn starts_operand(&self, token: &LexToken) -> bool {
+        let key = (token.ty, token.get_value());
+        if token.is_close_bracket(self) || key == (self.ternary.0, self.ternary.2)
+            || (token.ty == "lit" && (key.1 == self.list_sep || key.1 == self.terminator)) {
+            return false;
+        }
+        let fixity = self.fixity(token);
+        fixity.prefix || !(fixity.infix || fixity.postfix)
+    }
+
+    /// the tokens of a group between its opener and closer
+    fn group_inner<'a>(&self, token: &'a LexToken) -> &'a [LexToken] {
+        match token.subs.last() {
+            Some(last) if last.synthetic || self.is_closer_of(token, last) => {
+                &token.subs[..token.subs.len() - 1]
+            }
+            _ => &token.subs[..],
+        }
+    }
+
+    /// the expressions between `list_sep`s, a trailing separator is allowed
+    fn parse_items(&self, inner: &[LexToken], depth: usize) -> AstResult<Vec<LexToken>> {
+        let mut items = inner.split(|t| t.ty == "lit" && t.get_value() == self.list_sep).collect::<Vec<_>>();
+        if items.last().is_some_and(|item| item.is_empty()) {
+            items.pop();
+        }
+        items.into_iter().map(|item| self.parse_tokens(item, depth + 1)).collect()
+    }
+
+    fn parse_group(&self, token: &LexToken, depth: usize) -> AstResult<LexToken> {
+        let inner = self.group_inner(token);
+        if (token.ty, token.get_value()) != self.list_group {
+            if inner.is_empty() {
+                return Err(AstError::new_unexpected_token_error(token.clone_base_token()));
+            }
+            let mut expr = self.parse_tokens(inner, depth + 1)?;
+            expr.set_flag(LexToken::FLAG_GROUPED, true);
+            return Ok(expr);
+        }
+
+        let mut node = token.clone_base_token();
+        node.subs = self.parse_items(inner, depth)?;
+        Ok(node)
+    }
+
+    /// Reorder a flat token stream into reverse Polish notation by shunting-yard, without recursion.
+    /// Operators are the infix ones of `precs` and, where an operand belongs, the prefix ones which
+    /// come out with `FLAG_PREFIX` set. Brackets from `hash_matchs` group and are dropped.
+    pub fn to_rpn(&self, tokens: &[LexToken]) -> AstResult<Vec<LexToken>> {
+        let mut output = Vec::with_capacity(tokens.len());
+        // operators and openers waiting, with the level they bind at when they are prefix
+        let mut stack: Vec<(&LexToken, Option<i32>)> = vec![];
+        let mut want_operand = true;
+        let emit = |(token, prefix): (&LexToken, Option<i32>)| {
+            let mut token = token.clone_base_token();
+            token.set_flag(LexToken::FLAG_PREFIX, prefix.is_some());
+            token
+        };
+        for token in tokens {
+            let key = self.op_key(token);
+            let fixity = self.fixity(token);
+            if token.is_open_bracket(self) {
+                if !want_operand {
+                    return Err(AstError::new_unexpected_token_error(token.clone_base_token()));
+                }
+                stack.push((token, None));
+            } else if token.is_close_bracket(self) {
+                if want_operand {
+                    return Err(AstError::new_unexpected_token_error(token.clone_base_token()));
+                }
+                loop {
+                    match stack.pop() {
+                        Some((top, _)) if self.is_closer_of(top, token) => break,
+                        Some((top, _)) if top.is_open_bracket(self) => {
+                            return Err(AstError::new_no_match_close_error(top.clone_base_token()));
+                        }
+                        Some(top) => output.push(emit(top)),
+                        None => return Err(AstError::new_unexpected_token_error(token.clone_base_token())),
+                    }
+                }
+            } else if let (true, true, Some(&prec)) = (want_operand, fixity.prefix, self.prefix_hash.get(&key)) {
+                // as in `parse_unary`, everything binding at `prec` or tighter is its operand
+                stack.push((token, Some(prec)));
+            } else if let Some(&(left, prec)) = self.prec_hash.get(&key).filter(|_| fixity.infix) {
+                if want_operand {
+                    return Err(AstError::new_unexpected_token_error(token.clone_base_token()));
+                }
+                while let Some(&(top, prefix)) = stack.last() {
+                    let pops = match prefix {
+                        Some(top_prec) => top_prec > prec,
+                        None if top.is_open_bracket(self) => false,
+                        None => self.prec_hash.get(&self.op_key(top)).is_some_and(|&(_, top_prec)| top_prec > prec || (top_prec == prec && left)),
+                    };
+                    if !pops {
+                        break;
+                    }
+                    output.push(emit(stack.pop().unwrap()));
+                }
+                stack.push((token, None));
+                want_operand = true;
+            } else {
+                if !want_operand {
+                    return Err(AstError::new_unexpected_token_error(token.clone_base_token()));
+                }
+                output.push(token.clone_base_token());
+                want_operand = false;
+            }
+        }
+        if want_operand {
+            return Err(AstError::new_unexpected_eof_error());
+        }
+        while let Some(top) = stack.pop() {
+            if top.1.is_none() && top.0.is_open_bracket(self) {
+                return Err(AstError::new_no_match_close_error(top.0.clone_base_token()));
+            }
+            output.push(emit(top));
+        }
+        Ok(output)
+    }
+
+    /// Evaluate `to_rpn` output on an operand stack. Operators with `FLAG_PREFIX` and prefix only
+    /// ones go to `on_unary`, other infix operators of `precs` to `on_binary`, any other token is
+    /// read as an operand.
+    pub fn eval_rpn(&mut self, rpn: &[LexToken]) -> AstResult<AstAny> {
+        let mut stack: Vec<AstAny> = Vec::with_capacity(rpn.len());
+        for token in rpn {
+            self.step(token)?;
+            let key = self.op_key(token);
+            let fixity = self.fixity(token);
+            let underflow = || AstError::new_unexpected_token_error(token.clone_base_token());
+            let value = if token.has_flag(LexToken::FLAG_PREFIX) {
+                let value = stack.pop().ok_or_else(underflow)?;
+                self.handler.on_unary(token, value)?
+            } else if fixity.infix && self.prec_hash.contains_key(&key) {
+                let rhs = stack.pop().ok_or_else(underflow)?;
+                let lhs = stack.pop().ok_or_else(underflow)?;
+                self.handler.on_binary(token, lhs, rhs)?
+            } else if fixity.prefix && self.prefix_hash.contains_key(&key) {
+                let value = stack.pop().ok_or_else(underflow)?;
+                self.handler.on_unary(token, value)?
+            } else if !matches!(token.value, AstAny::Unknow) {
+                token.value.clone()
+            } else {
+                let mut operand = token.clone_base_token();
+                let ctx = LexerCtx { data: &self.data, prec_hash: &self.prec_hash };
+                self.handler.on_read_ctx(&mut operand, &ctx)?
+            };
+            stack.push(value);
+        }
+        match (stack.pop(), stack.is_empty()) {
+            (Some(value), true) => Ok(value),
+            (Some(_), false) => Err(AstError::new_unexpected_token_error(rpn[0].clone_base_token())),
+            (None, _) => Err(AstError::new_unexpected_eof_error()),
+        }
+    }
+
+    /// Evaluate an expression tree from `parse_expr` bottom-up through the handler,
+    /// each node keeps its result in `value`.
+    pub fn eval_expr(&mut self, expr: &mut LexToken) -> AstResult<AstAny> {
+        self.eval_depth(expr, 0)
+    }
+
+    /// take one handler call off `budget` for `token`
+    fn step(&mut self, token: &LexToken) -> AstResult<()> {
+        if let Some(steps) = self.budget.as_mut() {
+            if *steps == 0 {
+                return Err(AstError::new_budget_exceeded_error(token.clone_base_token()));
+            }
+            *steps -= 1;
+        }
+        Ok(())
+    }
+
+    /// whether `eval_depth` hands `expr` to `on_binary`
+    fn is_binary(&self, expr: &LexToken) -> bool {
+        let key = (expr.ty, expr.get_value());
+        expr.subs.len() == 2
+            && key != self.list_group
+            && key != self.call_group
+            && self.op_key(expr) != self.assign
+            && !self.mixfix.iter().any(|m| m.markers.first() == Some(&key.1) && m.markers.len() == 2)
+    }
+
+    fn eval_depth(&mut self, expr: &mut LexToken, depth: usize) -> AstResult<AstAny> {
+        if depth > self.max_depth {
+            return Err(AstError::new_too_deep_error(expr.clone_base_token()));
+        }
+        self.step(expr)?;
+        let mixfix = self.mixfix.iter()
+            .find(|m| m.markers.first() == Some(&expr.get_value()) && m.markers.len() == expr.subs.len())
+            .map(|m| m.name);
+        let value = if let Some(name) = mixfix {
+            let mut parts = vec![];
+            for sub in expr.subs.iter_mut() {
+                parts.push(self.eval_depth(sub, depth + 1)?);
+            }
+            self.handler.on_mixfix(name, parts)?
+        } else if (expr.ty, expr.get_value()) == self.list_group {
+            let mut items = vec![];
+            for sub in expr.subs.iter_mut() {
+                items.push(self.eval_depth(sub, depth + 1)?);
+            }
+            self.handler.on_list(expr, items)?
+        } else if (expr.ty, expr.get_value()) == self.call_group && !expr.subs.is_empty() {
+            let mut args = vec![];
+            for sub in expr.subs[1..].iter_mut() {
+                args.push(self.eval_depth(sub, depth + 1)?);
+            }
+            self.handler.on_call(&expr.subs[0], args)?
+        } else {
+            match expr.subs.len() {
+                0 if !matches!(expr.value, AstAny::Unknow) => expr.value.clone(),
+                0 => {
+                    let ctx = LexerCtx { data: &self.data, prec_hash: &self.prec_hash };
+                    self.handler.on_read_ctx(expr, &ctx)?
+                }
+                1 if expr.start >= expr.subs[0].end => {
+                    let value = self.eval_depth(&mut expr.subs[0], depth + 1)?;
+                    self.handler.on_postfix(expr, value)?
+                }
+                1 => {
+                    let value = self.eval_depth(&mut expr.subs[0], depth + 1)?;
+                    self.handler.on_unary(expr, value)?
+                }
+                3 => {
+                    let cond = self.eval_depth(&mut expr.subs[0], depth + 1)?;
+                    let idx = if self.handler.on_ternary(expr, &cond)? { 1 } else { 2 };
+                    self.eval_depth(&mut expr.subs[idx], depth + 1)?
+                }
+                2 if self.op_key(expr) == self.assign => {
+                    if !expr.subs[0].subs.is_empty() {
+                        return Err(AstError::new_unexpected_token_error(expr.subs[0].clone_base_token()));
+                    }
+                    let value = self.eval_depth(&mut expr.subs[1], depth + 1)?;
+                    self.handler.on_assign(&expr.subs[0], value)?
+                }
+                _ => {
+                    let lhs = self.eval_left_spine(expr, depth)?;
+                    let rhs = self.eval_depth(&mut expr.subs[1], depth + 1)?;
+                    self.handler.on_binary(expr, lhs, rhs)?
+                }
+            }
+        };
+        expr.value = value.clone();
+        Ok(value)
+    }
+
+    /// The value of the left operand of the binary `expr`. A left leaning chain like `1 + 2 + 3`
+    /// is walked down without recursing, so only nesting counts against `max_depth`.
+    fn eval_left_spine(&mut self, expr: &mut LexToken, depth: usize) -> AstResult<AstAny> {
+        let mut spine = vec![];
+        let mut lhs = expr.subs.remove(0);
+        while self.is_binary(&lhs) {
+            let next = lhs.subs.remove(0);
+            spine.push(lhs);
+            lhs = next;
+        }
+        let mut value = self.eval_depth(&mut lhs, depth + 1);
+        // put the operands back on the way up, also when evaluation failed
+        while let Some(mut node) = spine.pop() {
+            node.subs.insert(0, lhs);
+            value = value.and_then(|lhs| {
+                self.step(&node)?;
+                let rhs = self.eval_depth(&mut node.subs[1], depth + 1)?;
+                let value = self.handler.on_binary(&node, lhs, rhs)?;
+                node.value = value.clone();
+                Ok(value)
+            });
+            lhs = node;
+        }
+        expr.subs.insert(0, lhs);
+        value
+    }
+
+    /// A normal form of a `parse_expr` tree for comparing and caching. Aliases become their
+    /// canonical operator, the two operands of an operator in `commutative` are put in a fixed
+    /// order, and the tree is laid out afresh on a source of its own so positions and spelling
+    /// no longer tell two equivalent trees apart. An empty `commutative` reorders nothing.
+    pub fn canonicalize(&self, tree: &LexToken, commutative: &[&str]) -> LexToken {
+        fn feed(hash: &mut u64, bytes: &[u8]) {
+            for b in bytes {
+                *hash ^= *b as u64;
+                *hash = hash.wrapping_mul(0x100000001b3);
+            }
+        }
+        // bottom up, each node gets its op text as `data`, its subs in their final order and
+        // its offset in the text of its parent as `start`, with a hash and the length of the
+        // text of its subtree to order and place it by
+        type Shaped = (LexToken, u64, usize);
+        let mut stack: Vec<(&LexToken, Vec<Shaped>)> = vec![(tree, vec![])];
+        let (mut root, _, len) = loop {
+            let (token, done) = stack.last().unwrap();
+            if let Some(next) = token.subs.get(done.len()) {
+                stack.push((next, Vec::with_capacity(next.subs.len())));
+                continue;
+            }
+            let (token, mut subs) = stack.pop().unwrap();
+            let mut node = token.clone_base_token();
+            node.value = token.value.clone();
+            node.canonical = None;
+            node.captures.clear();
+            let mut op = token.get_value();
+            if !token.subs.is_empty() {
+                (node.ty, op) = self.op_key(token);
+            }
+            if subs.len() == 2 && commutative.contains(&op) {
+                subs.sort_by_key(|sub| sub.1);
+            }
+            let mut hash = 0xcbf29ce484222325;
+            for part in [node.type_name().as_bytes(), op.as_bytes()] {
+                feed(&mut hash, &(part.len() as u64).to_le_bytes());
+                feed(&mut hash, part);
+            }
+            (node.start, node.end) = (0, op.len());
+            let mut len = op.len();
+            for (mut sub, sub_hash, sub_len) in subs {
+                feed(&mut hash, &sub_hash.to_le_bytes());
+                len += 1;
+                (sub.start, sub.end) = (len, len + sub.end);
+                len += sub_len;
+                node.subs.push(sub);
+            }
+            node.data = Arc::new(op.to_string());
+            match stack.last_mut() {
+                Some((_, done)) => done.push((node, hash, len)),
+                None => break (node, hash, len),
+            }
+        };
+
+        // top down, write the text in pre-order and make the offsets absolute
+        let mut src = String::with_capacity(len);
+        let mut stack = vec![(&mut root, 0)];
+        while let Some((node, base)) = stack.pop() {
+            (node.start, node.end) = (node.start + base, node.end + base);
+            // the space before every node but the root
+            src.extend(std::iter::repeat_n(' ', node.start - src.len()));
+            src.push_str(&node.data);
+            let start = node.start;
+            stack.extend(node.subs.iter_mut().rev().map(|sub| (sub, start)));
+        }
+        let data = Arc::new(src);
+        let mut stack = vec![&mut root];
+        while let Some(node) = stack.pop() {
+            node.data = data.clone();
+            node.lineno = 1;
+            stack.extend(node.subs.iter_mut());
+        }
+        root
+    }
+
+    /// Replace every subtree whose leaves are all constants by one leaf holding the folded `value`,
+    /// spanning the whole subtree and typed as its first operand. Other subtrees are kept.
+    pub fn fold_constants(&mut self, tree: LexToken) -> AstResult<LexToken> {
+        // the nodes whose subs are being folded, with the subs still to come, walked
+        // without recursing so long chains fold like short ones
+        let mut stack: Vec<(LexToken, std::vec::IntoIter<LexToken>)> = vec![];
+        let mut node = tree;
+        loop {
+            if !node.subs.is_empty() {
+                let mut subs = std::mem::take(&mut node.subs).into_iter();
+                let first = subs.next().unwrap();
+                stack.push((node, subs));
+                node = first;
+                continue;
+            }
+            let mut folded = node;
+            node = loop {
+                let Some((parent, subs)) = stack.last_mut() else {
+                    return Ok(folded);
+                };
+                parent.subs.push(folded);
+                if let Some(next) = subs.next() {
+                    break next;
+                }
+                let (parent, _) = stack.pop().unwrap();
+                folded = self.fold_node(parent)?;
+            };
+        }
+    }
+
+    /// `tree` with its subs already folded, as one leaf when they are all constants
+    fn fold_node(&mut self, mut tree: LexToken) -> AstResult<LexToken> {
+        let constant = tree.subs.iter().all(|t| {
+            t.subs.is_empty() && (!matches!(t.value, AstAny::Unknow) || self.handler.is_const(t))
+        });
+        if !constant {
+            return Ok(tree);
+        }
+
+        let value = self.eval_expr(&mut tree)?;
+        let first = tree.subs.iter().chain([&tree]).min_by_key(|t| t.start).unwrap();
+        let mut token = first.clone_base_token();
+        token.ty = tree.subs[0].ty;
+        token.name = tree.subs[0].name.clone();
+        token.end = tree.subs.iter().chain([&tree]).map(|t| t.end).max().unwrap();
+        token.value = value;
+        Ok(token)
+    }
+}
@@ -0,0 +1,7 @@
+use crate::SourceMap;
+
+/// A pass run over the source before lexing, such as tab expansion,
+/// the map leads token offsets back to the original text.
+pub trait Preprocessor {
+    fn process(&self, src: &str) -> (String, SourceMap);
+}
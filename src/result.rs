@@ -12,7 +12,21 @@ pub type AstResult<T> = StdResult<T, AstError>;
 #[derive(Debug)]
 pub enum Kind {
     Internal,
-    NoMatchClose(LexToken),
+    NoMatchClose(Box<LexToken>),
+    OutOfRange(usize, usize),
+    UnexpectedToken(Box<LexToken>),
+    UnexpectedEof,
+    TooDeep(Box<LexToken>),
+    InvalidEscape(usize),
+    UnknownChar(char, usize),
+    Undefined(Box<LexToken>),
+    InvalidBytes(usize),
+    IntOverflow(Box<LexToken>),
+    TooManyTokens(usize),
+    InvalidEncoding(usize),
+    ConfigConflict(char),
+    BudgetExceeded(Box<LexToken>),
+    IdentTooLong(Box<LexToken>),
     ParseIntError(num::ParseIntError),
     ParseFloatError(num::ParseFloatError),
     Custom(Box<dyn StdError + Send + Sync>),
@@ -44,10 +58,121 @@ impl AstError {
 
     pub fn new_no_match_close_error(token: LexToken) -> AstError {
         AstError {
-            kind: Kind::NoMatchClose(token),
+            kind: Kind::NoMatchClose(Box::new(token)),
             details: "".into(),
         }
     }
+
+    pub fn new_out_of_range_error(start: usize, end: usize) -> AstError {
+        AstError {
+            kind: Kind::OutOfRange(start, end),
+            details: "".into(),
+        }
+    }
+
+    pub fn new_unexpected_token_error(token: LexToken) -> AstError {
+        AstError {
+            kind: Kind::UnexpectedToken(Box::new(token)),
+            details: "".into(),
+        }
+    }
+
+    pub fn new_unexpected_eof_error() -> AstError {
+        AstError {
+            kind: Kind::UnexpectedEof,
+            details: "".into(),
+        }
+    }
+
+    pub fn new_too_deep_error(token: LexToken) -> AstError {
+        AstError {
+            kind: Kind::TooDeep(Box::new(token)),
+            details: "".into(),
+        }
+    }
+
+    pub fn new_invalid_escape_error(pos: usize) -> AstError {
+        AstError {
+            kind: Kind::InvalidEscape(pos),
+            details: "".into(),
+        }
+    }
+
+    pub fn new_unknown_char_error(ch: char, pos: usize) -> AstError {
+        AstError {
+            kind: Kind::UnknownChar(ch, pos),
+            details: "".into(),
+        }
+    }
+
+    pub fn new_undefined_error(token: LexToken) -> AstError {
+        AstError {
+            kind: Kind::Undefined(Box::new(token)),
+            details: "".into(),
+        }
+    }
+
+    pub fn new_invalid_bytes_error(pos: usize) -> AstError {
+        AstError {
+            kind: Kind::InvalidBytes(pos),
+            details: "".into(),
+        }
+    }
+
+    pub fn new_int_overflow_error(token: LexToken) -> AstError {
+        AstError {
+            kind: Kind::IntOverflow(Box::new(token)),
+            details: "".into(),
+        }
+    }
+
+    pub fn new_too_many_tokens_error(pos: usize) -> AstError {
+        AstError {
+            kind: Kind::TooManyTokens(pos),
+            details: "".into(),
+        }
+    }
+
+    pub fn new_invalid_encoding_error(pos: usize) -> AstError {
+        AstError {
+            kind: Kind::InvalidEncoding(pos),
+            details: "".into(),
+        }
+    }
+
+    pub fn new_config_conflict_error(ch: char) -> AstError {
+        AstError {
+            kind: Kind::ConfigConflict(ch),
+            details: "".into(),
+        }
+    }
+
+    pub fn new_budget_exceeded_error(token: LexToken) -> AstError {
+        AstError {
+            kind: Kind::BudgetExceeded(Box::new(token)),
+            details: "".into(),
+        }
+    }
+
+    pub fn new_ident_too_long_error(token: LexToken) -> AstError {
+        AstError {
+            kind: Kind::IdentTooLong(Box::new(token)),
+            details: "".into(),
+        }
+    }
+
+    /// the byte range in the lexed source the error points at, when it has one
+    pub fn span(&self) -> Option<(usize, usize)> {
+        match &self.kind {
+            Kind::NoMatchClose(token) | Kind::UnexpectedToken(token) | Kind::TooDeep(token)
+            | Kind::Undefined(token) | Kind::IntOverflow(token) | Kind::BudgetExceeded(token)
+            | Kind::IdentTooLong(token) => Some((token.start, token.end)),
+            Kind::OutOfRange(start, end) => Some((*start, *end)),
+            Kind::InvalidEscape(pos) | Kind::TooManyTokens(pos) => Some((*pos, *pos)),
+            Kind::UnknownChar(ch, pos) => Some((*pos, pos + ch.len_utf8())),
+            _ => None,
+        }
+    }
 }
 
 
@@ -66,7 +191,7 @@ impl From<num::ParseFloatError> for AstError {
 
 impl fmt::Debug for AstError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.details.len() > 0 {
+        if !self.details.is_empty() {
             write!(f, "AST Error <{:?}>: {}", self.kind, self.details)
         } else {
             write!(f, "AST Error <{:?}>", self.kind)
@@ -74,9 +199,10 @@ impl fmt::Debug for AstError {
     }
 }
 
+#[allow(deprecated)]
 impl fmt::Display for AstError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        if self.details.len() > 0 {
+        if !self.details.is_empty() {
             write!(f, "{}: {}", self.description(), self.details)
         } else {
             write!(f, "{}", self.description())
@@ -84,11 +210,26 @@ impl fmt::Display for AstError {
     }
 }
 
+#[allow(deprecated)]
 impl StdError for AstError {
     fn description(&self) -> &str {
         match self.kind {
             Kind::Internal => "Internal Application Error",
             Kind::NoMatchClose(_) => "Not Match close Error", 
+            Kind::OutOfRange(_, _) => "Out of range Error",
+            Kind::UnexpectedToken(_) => "Unexpected token Error",
+            Kind::UnexpectedEof => "Unexpected eof Error",
+            Kind::TooDeep(_) => "Nesting too deep Error",
+            Kind::InvalidEscape(_) => "Invalid escape Error",
+            Kind::UnknownChar(_, _) => "Unknown char Error",
+            Kind::Undefined(_) => "Undefined name Error",
+            Kind::InvalidBytes(_) => "Invalid bytes Error",
+            Kind::IntOverflow(_) => "Integer overflow Error",
+            Kind::TooManyTokens(_) => "Too many tokens Error",
+            Kind::InvalidEncoding(_) => "Invalid encoding Error",
+            Kind::ConfigConflict(_) => "Config conflict Error",
+            Kind::BudgetExceeded(_) => "Budget exceeded Error",
+            Kind::IdentTooLong(_) => "Identifier too long Error",
             Kind::ParseIntError(_) => "parse Int Error",
             Kind::ParseFloatError(_) => "parse Float Error",
             Kind::Custom(ref err) => err.description(),
@@ -96,9 +237,7 @@ impl StdError for AstError {
     }
 
     fn cause(&self) -> Option<&dyn StdError> {
-        match self.kind {
-            _ => None,
-        }
+        None
     }
 }
 
@@ -0,0 +1,105 @@
+use crate::{Handler, Lexer, LexToken};
+
+/// A plain copy of a grouped token for comparing in tests, no source or offsets attached.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseNode {
+    pub ty: &'static str,
+    pub text: String,
+    /// 1-based line and char column of the start
+    pub line: usize,
+    pub col: usize,
+    pub children: Vec<ParseNode>,
+}
+
+impl ParseNode {
+    pub fn new(ty: &'static str, text: &str, line: usize, col: usize, children: Vec<ParseNode>) -> ParseNode {
+        ParseNode { ty, text: text.to_string(), line, col, children }
+    }
+}
+
+impl<H: Handler> Lexer<H> {
+    /// `tokenstack` as `ParseNode`s
+    pub fn to_parse_tree(&self) -> Vec<ParseNode> {
+        // the tokens being copied with their children so far, deep nesting needs no recursion
+        let mut roots = vec![];
+        for root in &self.tokenstack {
+            let mut stack: Vec<(&LexToken, Vec<ParseNode>)> = vec![(root, vec![])];
+            while let Some((token, children)) = stack.last() {
+                if let Some(next) = token.subs.get(children.len()) {
+                    stack.push((next, Vec::with_capacity(next.subs.len())));
+                    continue;
+                }
+                let (token, children) = stack.pop().unwrap();
+                let (line, col) = self.line_col(token.start);
+                let node = ParseNode::new(token.ty, token.get_value(), line, col, children);
+                match stack.last_mut() {
+                    Some((_, done)) => done.push(node),
+                    None => roots.push(node),
+                }
+            }
+        }
+        roots
+    }
+}
+
+/// A grouped token list flattened into stable ids in source order,
+/// each node is the token without its `subs`.
+#[derive(Clone, Debug, Default)]
+pub struct TokenTree {
+    nodes: Vec<LexToken>,
+    parents: Vec<Option<usize>>,
+    children: Vec<Vec<usize>>,
+    roots: Vec<usize>,
+}
+
+impl TokenTree {
+    pub fn new(tokens: &[LexToken]) -> TokenTree {
+        let mut tree = TokenTree::default();
+        let mut stack: Vec<(&LexToken, Option<usize>)> = tokens.iter().rev().map(|t| (t, None)).collect();
+        while let Some((token, parent)) = stack.pop() {
+            let id = tree.nodes.len();
+            tree.nodes.push(token.clone_base_token());
+            tree.parents.push(parent);
+            tree.children.push(vec![]);
+            match parent {
+                Some(parent) => tree.children[parent].push(id),
+                None => tree.roots.push(id),
+            }
+            stack.extend(token.subs.iter().rev().map(|t| (t, Some(id))));
+        }
+        tree
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn get(&self, id: usize) -> Option<&LexToken> {
+        self.nodes.get(id)
+    }
+
+    pub fn roots(&self) -> &[usize] {
+        &self.roots
+    }
+
+    pub fn parent(&self, id: usize) -> Option<usize> {
+        self.parents.get(id).copied().flatten()
+    }
+
+    pub fn children(&self, id: usize) -> &[usize] {
+        self.children.get(id).map_or(&[], |c| &c[..])
+    }
+
+    pub fn next_sibling(&self, id: usize) -> Option<usize> {
+        let siblings = match self.parents.get(id)? {
+            Some(parent) => &self.children[*parent],
+            None => &self.roots,
+        };
+        let idx = siblings.iter().position(|s| *s == id)?;
+        siblings.get(idx + 1).copied()
+    }
+}
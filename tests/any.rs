@@ -0,0 +1,57 @@
+mod common;
+
+use common::*;
+use lang_ast::*;
+use regex::Regex;
+
+#[test]
+fn integers_parse_without_overflowing() {
+    let src = "9223372036854775807 9223372036854775808 0xFFFF_FFFF_FFFF_FFFF_FFFF 0x7fff_ffff_ffff_ffff \
+        99999999999999999999999999999999999999999999 0x 12 -5 +5 -9223372036854775808 -0x10 --5 - +-1 1_2z";
+    let mut lex = Lexer::new(src.to_string(), DefaultHandler);
+    lex.add_regex("num", Regex::new(r"\S+").unwrap());
+    let tokens = lex.tokenize().unwrap();
+    let parse = |idx: usize, overflow| AstAny::parse_int(&tokens[idx], overflow);
+    assert_eq!(parse(0, IntOverflow::Error).unwrap(), AstAny::I64(i64::MAX));
+    assert_eq!(kind(&parse(1, IntOverflow::Error).unwrap_err()), "IntOverflow");
+    assert_eq!(parse(1, IntOverflow::Promote).unwrap(), AstAny::I128(i64::MAX as i128 + 1));
+    assert_eq!(kind(&parse(2, IntOverflow::Error).unwrap_err()), "IntOverflow");
+    assert_eq!(parse(2, IntOverflow::Promote).unwrap(), AstAny::I128(0xFFFF_FFFF_FFFF_FFFF_FFFF));
+    assert_eq!(parse(3, IntOverflow::Error).unwrap(), AstAny::I64(i64::MAX));
+    assert!(matches!(parse(4, IntOverflow::Promote).unwrap(), AstAny::F64(f) if f > 9.9e43));
+    assert_eq!(kind(&parse(5, IntOverflow::Promote).unwrap_err()), "ParseIntError");
+    assert_eq!(parse(6, IntOverflow::Error).unwrap(), AstAny::I64(12));
+    assert_eq!(parse(7, IntOverflow::Error).unwrap(), AstAny::I64(-5));
+    assert_eq!(parse(8, IntOverflow::Error).unwrap(), AstAny::I64(5));
+    assert_eq!(parse(9, IntOverflow::Error).unwrap(), AstAny::I64(i64::MIN));
+    assert_eq!(parse(10, IntOverflow::Error).unwrap(), AstAny::I64(-16));
+    for token in &tokens[11..] {
+        assert_eq!(kind(&AstAny::parse_int(token, IntOverflow::Promote).unwrap_err()), "ParseIntError", "{}", token.get_value());
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Point {
+    x: usize,
+    y: usize,
+}
+
+/// reads each token as the `Point` of its span
+struct Points;
+
+impl Handler for Points {
+    fn on_read(&mut self, token: &mut LexToken) -> AstResult<AstAny> {
+        Ok(AstAny::custom(Point { x: token.start, y: token.end }))
+    }
+}
+
+#[test]
+fn handlers_keep_their_own_values() {
+    let mut lex = Lexer::new("ab".to_string(), Points);
+    lex.add_regex("id", Regex::new(r"\w+").unwrap());
+    let value = lex.eval().unwrap();
+    assert_eq!(value.as_custom::<Point>(), Some(&Point { x: 0, y: 2 }));
+    assert_eq!(value.as_custom::<i32>(), None);
+    assert_eq!(value.clone(), value);
+    assert!(value.is_truthy());
+}
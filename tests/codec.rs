@@ -0,0 +1,57 @@
+mod common;
+
+use common::*;
+use lang_ast::*;
+use std::sync::Arc;
+
+#[test]
+fn token_trees_round_trip() {
+    let mut lex = lexer("f(a, [b, {c}]) \"é\"");
+    lex.add_string("\"", "str");
+    lex.set_escape_decoder(DefaultEscapeDecoder);
+    lex.parser_token().unwrap();
+    let mut tokens = lex.tokenstack.clone();
+    tokens[0].value = AstAny::List(vec![AstAny::I64(-5), AstAny::F64(1.5), AstAny::Char('x'), AstAny::U128(u128::MAX)]);
+    let bytes = LexToken::to_bytes(&tokens);
+    let back = LexToken::from_bytes(&bytes, &lex.known_types()).unwrap();
+    assert_eq!(back, tokens);
+    assert_eq!(back[1].subs[2].subs[2].subs[0].get_value(), "c");
+    assert!(Arc::ptr_eq(&back[0].data, &back[1].subs[0].data));
+    for n in 0..bytes.len() {
+        assert!(LexToken::from_bytes(&bytes[..n], &lex.known_types()).is_err());
+    }
+}
+
+#[test]
+fn decoding_only_accepts_known_types() {
+    let mut lex = lexer("a");
+    let mut tokens = lex.tokenize().unwrap();
+    tokens[0] = tokens[0].clone().with_type("made");
+    let bytes = LexToken::to_bytes(&tokens);
+    assert_eq!(kind(&LexToken::from_bytes(&bytes, &lex.known_types()).unwrap_err()), "InvalidBytes");
+    lex.extra_types.push("made");
+    assert_eq!(LexToken::from_bytes(&bytes, &lex.known_types()).unwrap()[0].ty, "made");
+}
+
+#[test]
+fn deep_trees_do_not_overflow() {
+    let mut lex = lexer("a");
+    let leaf = lex.tokenize().unwrap().remove(0);
+    let mut tree = leaf.clone();
+    for _ in 0..5000 {
+        let mut group = leaf.clone();
+        group.subs.push(tree);
+        tree = group;
+    }
+    let tokens = vec![tree];
+    let back = LexToken::from_bytes(&LexToken::to_bytes(&tokens), &lex.known_types()).unwrap();
+    assert_eq!(back, tokens);
+
+    let mut value = AstAny::Unsport;
+    for _ in 0..5000 {
+        value = AstAny::List(vec![value]);
+    }
+    let mut tokens = vec![leaf];
+    tokens[0].value = value;
+    assert!(LexToken::from_bytes(&LexToken::to_bytes(&tokens), &lex.known_types()).is_err());
+}
@@ -0,0 +1,75 @@
+#![allow(dead_code)]
+
+use lang_ast::{AstAny, AstResult, DefaultHandler, Handler, LexToken, Lexer};
+use regex::Regex;
+
+/// a lexer with the `id` and `num` rules most tests need
+pub fn lexer(src: &str) -> Lexer<DefaultHandler> {
+    let mut lex = Lexer::new(src.to_string(), DefaultHandler);
+    lex.add_regex("id", Regex::new(r"[A-Za-z_][A-Za-z0-9_]*").unwrap());
+    lex.add_regex("num", Regex::new(r"\d+").unwrap());
+    lex
+}
+
+/// every remaining token as `(ty, text)`
+pub fn values<H: Handler>(lex: &mut Lexer<H>) -> Vec<(&'static str, String)> {
+    let mut found = vec![];
+    while let Some(token) = lex.get_token().unwrap() {
+        found.push((token.ty, token.get_value().to_string()));
+    }
+    found
+}
+
+/// integer arithmetic on `num` tokens
+pub struct Calc;
+
+impl Handler for Calc {
+    fn on_read(&mut self, token: &mut LexToken) -> AstResult<AstAny> {
+        match token.ty {
+            "num" => Ok(AstAny::I64(token.get_value().parse()?)),
+            _ => Ok(AstAny::Unsport),
+        }
+    }
+
+    fn on_unary(&mut self, op: &LexToken, value: AstAny) -> AstResult<AstAny> {
+        match (op.op_text(), value) {
+            ("-", AstAny::I64(v)) => Ok(AstAny::I64(-v)),
+            _ => Ok(AstAny::Unsport),
+        }
+    }
+
+    fn on_binary(&mut self, op: &LexToken, lhs: AstAny, rhs: AstAny) -> AstResult<AstAny> {
+        match (op.op_text(), lhs, rhs) {
+            ("+", AstAny::I64(a), AstAny::I64(b)) => Ok(AstAny::I64(a + b)),
+            ("-", AstAny::I64(a), AstAny::I64(b)) => Ok(AstAny::I64(a - b)),
+            ("*", AstAny::I64(a), AstAny::I64(b)) => Ok(AstAny::I64(a * b)),
+            _ => Ok(AstAny::Unsport),
+        }
+    }
+}
+
+pub fn calc(src: &str) -> Lexer<Calc> {
+    let mut lex = Lexer::new(src.to_string(), Calc);
+    lex.add_regex("id", Regex::new(r"[A-Za-z_][A-Za-z0-9_]*").unwrap());
+    lex.add_regex("num", Regex::new(r"\d+").unwrap());
+    lex
+}
+
+/// lex, group and parse the whole source
+pub fn tree<H: Handler>(lex: &mut Lexer<H>) -> LexToken {
+    lex.parser_token().unwrap();
+    lex.parse_expr(&lex.tokenstack).unwrap()
+}
+
+pub fn int(value: &AstAny) -> i64 {
+    match value {
+        AstAny::I64(v) => *v,
+        other => panic!("not an I64: {:?}", other),
+    }
+}
+
+/// the name of the error kind, `Kind` is not exported
+pub fn kind(err: &lang_ast::AstError) -> String {
+    let debug = format!("{:?}", err.kind);
+    debug.split(['(', ' ']).next().unwrap().to_string()
+}
@@ -0,0 +1,867 @@
+mod common;
+
+use common::*;
+use lang_ast::*;
+use regex::Regex;
+
+#[test]
+fn tokenize_range_keeps_absolute_offsets() {
+    let mut lex = lexer("xx a+b yy");
+    let tokens = lex.tokenize_range(3, 6).unwrap();
+    let spans: Vec<_> = tokens.iter().map(|t| (t.get_value(), t.start, t.end)).collect();
+    assert_eq!(spans, vec![("a", 3, 4), ("+", 4, 5), ("b", 5, 6)]);
+    assert_eq!(lex.pos, 0);
+}
+
+#[test]
+fn tokenize_range_rejects_bad_bounds() {
+    let mut lex = lexer("xx a+b yy");
+    assert_eq!(kind(&lex.tokenize_range(5, 4).unwrap_err()), "OutOfRange");
+    assert!(lex.tokenize_range(0, 100).is_err());
+    let mut lex = lexer("é");
+    assert!(lex.tokenize_range(1, 2).is_err());
+}
+
+#[test]
+fn classifies_operators_and_brackets() {
+    let mut lex = lexer("+ ( ) a");
+    let tokens = lex.tokenize().unwrap();
+    let classes: Vec<_> = tokens.iter()
+        .map(|t| (t.is_operator(&lex), t.is_open_bracket(&lex), t.is_close_bracket(&lex)))
+        .collect();
+    assert_eq!(classes, vec![(true, false, false), (false, true, false), (false, false, true), (false, false, false)]);
+}
+
+#[test]
+fn emits_newline_tokens() {
+    let mut lex = lexer("a\nb\n\n  \nc");
+    lex.emit_newline = true;
+    let found = values(&mut lex);
+    assert_eq!(found.iter().filter(|t| t.0 == "newline").count(), 4);
+}
+
+#[test]
+fn collapses_blank_lines_into_one_newline() {
+    let mut lex = lexer("a\nb\n\n  \nc");
+    lex.emit_newline = true;
+    lex.collapse_newline = true;
+    let found = values(&mut lex);
+    let want = [("id", "a"), ("newline", "\n"), ("id", "b"), ("newline", "\n\n  \n"), ("id", "c")];
+    assert_eq!(found, want.map(|(ty, text)| (ty, text.to_string())));
+}
+
+#[test]
+fn matches_keywords_case_insensitively() {
+    let mut lex = lexer("SELECT Select select other");
+    lex.add_keyword("select", "kw");
+    lex.case_insensitive_keywords = true;
+    let types: Vec<_> = values(&mut lex).into_iter().map(|t| t.0).collect();
+    assert_eq!(types, vec!["kw", "kw", "kw", "id"]);
+    let mut lex = lexer("SELECT select");
+    lex.add_keyword("select", "kw");
+    let types: Vec<_> = values(&mut lex).into_iter().map(|t| t.0).collect();
+    assert_eq!(types, vec!["id", "kw"]);
+}
+
+#[test]
+fn reads_heredocs() {
+    let mut lex = lexer("x <<END\nline 1\n  END x\nEND\ny");
+    lex.add_heredoc("<<", "heredoc");
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!((tokens[1].ty, tokens[1].get_value()), ("heredoc", "<<END\nline 1\n  END x\nEND"));
+    assert_eq!((tokens[2].get_value(), tokens[2].lineno), ("y", 5));
+}
+
+#[test]
+fn unterminated_heredoc_fails() {
+    let mut lex = lexer("<<END\nabc\n");
+    lex.add_heredoc("<<", "heredoc");
+    assert_eq!(kind(&lex.get_token().unwrap_err()), "NoMatchClose");
+    let mut lex = lexer("a << b");
+    lex.add_heredoc("<<", "heredoc");
+    assert_eq!(lex.tokenize().unwrap().len(), 4);
+}
+
+#[test]
+fn splits_interpolated_strings() {
+    let mut lex = lexer(r#""plain" "hello ${name + {1}} x" "cost \${x}" 'q\'s'"#);
+    lex.add_interp_string("\"", "str");
+    lex.add_string("'", "chr");
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!(tokens.len(), 4);
+    let parts: Vec<_> = tokens[1].subs.iter().map(|t| (t.ty, t.get_value())).collect();
+    assert_eq!(parts, vec![("text", "hello "), ("interp", "${"), ("text", " x")]);
+    assert_eq!(tokens[1].subs[1].subs.len(), 6);
+    assert_eq!(tokens[2].subs.len(), 1);
+    assert_eq!(tokens[3].get_value(), r"'q\'s'");
+}
+
+#[test]
+fn unterminated_strings_fail() {
+    let mut lex = lexer(r#""abc"#);
+    lex.add_string("\"", "str");
+    assert!(lex.get_token().is_err());
+    let mut lex = lexer(r#""a ${b""#);
+    lex.add_interp_string("\"", "str");
+    assert!(lex.get_token().is_err());
+}
+
+#[test]
+fn skips_inactive_directive_branches() {
+    let src = "a\n#if FOO\nb\n#else\nc\n#endif\n  #ifndef FOO\nd\n#endif\ne";
+    let mut lex = lexer(src);
+    lex.add_directive_prefix("#");
+    lex.define("FOO");
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!(tokens.iter().map(|t| t.get_value()).collect::<Vec<_>>(), vec!["a", "b", "e"]);
+    assert_eq!(tokens[2].lineno, 10);
+    // lexing again from the start sees the same line starts
+    assert_eq!(lex.tokenize().unwrap(), tokens);
+    let mut lex = lexer(src);
+    lex.add_directive_prefix("#");
+    let texts: Vec<_> = values(&mut lex).into_iter().map(|t| t.1).collect();
+    assert_eq!(texts, vec!["a", "c", "d", "e"]);
+}
+
+#[test]
+fn unbalanced_directives_fail() {
+    let mut lex = lexer("#if X\na");
+    lex.add_directive_prefix("#");
+    assert_eq!(kind(&lex.get_token().unwrap_err()), "NoMatchClose");
+    let mut lex = lexer("#endif");
+    lex.add_directive_prefix("#");
+    assert!(lex.get_token().is_err());
+}
+
+#[test]
+fn runs_transformers_in_order() {
+    let mut lex = lexer("(a b) c");
+    lex.add_transformer(|token| vec![if token.ty == "id" { token.with_type("name") } else { token }]);
+    lex.add_transformer(|token| if token.get_value() == "b" { vec![token.clone(), token] } else { vec![token] });
+    lex.parser_token().unwrap();
+    assert_eq!(lex.tokenstack.len(), 2);
+    let types: Vec<_> = lex.tokenstack[0].subs.iter().map(|t| t.ty).collect();
+    assert_eq!(types, vec!["name", "name", "name", "lit"]);
+    assert_eq!(lex.tokenstack[1].ty, "name");
+}
+
+#[test]
+fn longest_match_policy_picks_the_longest_rule() {
+    let make = || {
+        let mut lex = Lexer::new("ifx if".to_string(), DefaultHandler);
+        lex.add_regex("kw", Regex::new("if").unwrap());
+        lex.add_regex("id", Regex::new("[a-z]+").unwrap());
+        lex
+    };
+    let texts = |lex: &mut Lexer<DefaultHandler>| values(lex).into_iter().map(|(ty, text)| format!("{}:{}", ty, text)).collect::<Vec<_>>();
+    assert_eq!(texts(&mut make()), vec!["kw:if", "id:x", "kw:if"]);
+    let mut lex = make();
+    lex.match_policy = MatchPolicy::LongestMatch;
+    assert_eq!(texts(&mut lex), vec!["id:ifx", "kw:if"]);
+}
+
+fn decoded(src: &str) -> AstResult<AstAny> {
+    let mut lex = lexer(src);
+    lex.add_string("\"", "str");
+    lex.set_escape_decoder(DefaultEscapeDecoder);
+    lex.get_token().map(|t| t.unwrap().value.clone())
+}
+
+#[test]
+fn decodes_string_escapes() {
+    assert_eq!(decoded(r#""a\nb""#).unwrap(), AstAny::Str("a\nb".to_string()));
+    assert_eq!(decoded(r#""é\t\"A""#).unwrap(), AstAny::Str("é\t\"A".to_string()));
+}
+
+#[test]
+fn invalid_escapes_fail_at_their_position() {
+    let err = decoded(r#"  "\uZZZZ""#).unwrap_err();
+    assert_eq!(err.span(), Some((3, 3)));
+    for src in [r#""\u+041""#, r#""\u-041""#, r#""\u 041""#, r#""\u12""#, r#""x\q""#] {
+        assert_eq!(kind(&decoded(src).unwrap_err()), "InvalidEscape", "{}", src);
+    }
+}
+
+#[test]
+fn set_regexes_replaces_every_rule() {
+    let mut lex = lexer("ab12");
+    assert_eq!(lex.tokenize().unwrap().iter().map(|t| t.ty).collect::<Vec<_>>(), vec!["id"]);
+    lex.set_regexes(vec![LexRegex::new("alpha", Regex::new("[a-z]+").unwrap()), LexRegex::new("num", Regex::new(r"\d+").unwrap())]);
+    assert_eq!(lex.tokenize().unwrap().iter().map(|t| t.ty).collect::<Vec<_>>(), vec!["alpha", "num"]);
+}
+
+/// answers unknown chars by skipping them, turning them into `error` tokens, or failing
+struct Unknown(u8);
+
+impl Handler for Unknown {
+    fn on_unknown(&mut self, ch: char, pos: usize) -> AstResult<Option<LexToken>> {
+        match self.0 {
+            0 => Ok(None),
+            1 => {
+                // any token will do, the lexer puts it on its own source
+                let mut token = lexer("x").tokenize().unwrap().remove(0).with_type("error");
+                (token.start, token.end) = (pos, pos + ch.len_utf8());
+                Ok(Some(token))
+            }
+            _ => Err(AstError::new_unknown_char_error(ch, pos)),
+        }
+    }
+}
+
+#[test]
+fn unknown_chars_go_to_the_handler() {
+    let id = Regex::new("[a-z]+").unwrap();
+    for (mode, want) in [(0u8, Some(vec!["a", "b"])), (1, Some(vec!["a", "€", "b"])), (2, None)] {
+        let mut lex = Lexer::new("a €\nb".to_string(), Unknown(mode));
+        lex.add_regex("id", id.clone());
+        match (lex.tokenize(), want) {
+            (Ok(tokens), Some(want)) => {
+                assert_eq!(tokens.iter().map(|t| t.get_value()).collect::<Vec<_>>(), want);
+                assert_eq!(tokens.last().unwrap().lineno, 2);
+            }
+            (Err(err), None) => assert_eq!(kind(&err), "UnknownChar"),
+            (found, _) => panic!("mode {}: {:?}", mode, found),
+        }
+    }
+}
+
+#[test]
+fn ascii_fast_path_lexes_like_the_utf8_path() {
+    let src = "abc = (x1 + 22) * \"s\" ;\n".repeat(3);
+    let run = |fast: bool| {
+        let mut lex = lexer(&src);
+        lex.add_string("\"", "str");
+        assert!(lex.is_ascii);
+        lex.is_ascii = fast;
+        lex.tokenize().unwrap().iter().map(|t| (t.ty, t.start, t.end, t.lineno)).collect::<Vec<_>>()
+    };
+    assert_eq!(run(true), run(false));
+    assert!(!lexer("é").is_ascii);
+}
+
+#[test]
+fn rule_priorities_are_read_on_every_token() {
+    let mut lex = Lexer::new("ab".to_string(), DefaultHandler);
+    lex.add_regex("lower", Regex::new("[a-z]+").unwrap());
+    lex.add_regex_prio("word", Regex::new("[a-z]+").unwrap(), 1);
+    assert_eq!(lex.tokenize().unwrap()[0].ty, "word");
+    lex.res[0].priority = 2;
+    assert_eq!(lex.tokenize().unwrap()[0].ty, "lower");
+}
+
+#[test]
+fn tokens_remember_their_rule() {
+    let mut lex = Lexer::new("ab 12 +".to_string(), DefaultHandler);
+    lex.add_regex("word", Regex::new("[a-z]+").unwrap());
+    lex.add_regex("word", Regex::new(r"\d+").unwrap());
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!(tokens.iter().map(|t| t.rule_id()).collect::<Vec<_>>(), vec![Some(0), Some(1), None]);
+    // the rule is bookkeeping, it does not take part in equality
+    let mut other = tokens[0].clone();
+    other.rule = Some(9);
+    assert_eq!(other, tokens[0]);
+    assert_ne!(tokens[0], tokens[1]);
+}
+
+#[test]
+fn split_operators_are_reported() {
+    let mut lex = lexer("a >= b > = c (x<=y) >=");
+    lex.precs.push(LexPrec::new("lit", true, vec![">=", "<="]));
+    let want = vec![(2, ">=".to_string()), (15, "<=".to_string()), (20, ">=".to_string())];
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!(lex.report_suspicious_splits(&tokens), want);
+    // grouped tokens are searched too
+    lex.parser_token().unwrap();
+    let tokens = lex.tokenstack.clone();
+    assert_eq!(lex.report_suspicious_splits(&tokens), want);
+}
+
+#[test]
+fn tokens_are_found_by_column() {
+    let mut lex = lexer("ab\t1\n\"é\"\t2\nxyzwvu 3");
+    lex.add_string("\"", "str");
+    lex.emit_newline = false;
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!(tokens.iter().map(|t| lex.column(t.start)).collect::<Vec<_>>(), vec![1, 5, 1, 5, 1, 8]);
+    let found = |lex: &Lexer<DefaultHandler>, from, to| {
+        lex.tokens_in_column_range(&tokens, from, to).iter().map(|t| t.get_value().to_string()).collect::<Vec<_>>()
+    };
+    assert_eq!(found(&lex, 5, 5), vec!["1", "2"]);
+    lex.tab_width = 8;
+    assert_eq!(found(&lex, 2, 9), vec!["1", "2", "3"]);
+}
+
+#[test]
+fn counting_tokens_consumes_them() {
+    let mut lex = lexer("(a+b)");
+    assert_eq!(lex.count_tokens().unwrap(), 5);
+    assert_eq!(lex.count_tokens().unwrap(), 0);
+    assert_eq!(lex.tokenize().unwrap().len(), 5);
+}
+
+#[test]
+fn identifiers_take_extra_chars() {
+    let mut lex = lexer("font-size a - b list? a-1 x-y-z");
+    lex.set_ident_extra("-?");
+    let found = values(&mut lex).into_iter().map(|(_, text)| text).collect::<Vec<_>>();
+    assert_eq!(found, vec!["font-size", "a", "-", "b", "list?", "a", "-", "1", "x-y-z"]);
+}
+
+#[test]
+fn source_between_tokens() {
+    let mut lex = lexer("x = ( a + b ) ;");
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!(lex.slice_between(&tokens[2], &tokens[6]).unwrap(), "( a + b )");
+    assert_eq!(lex.slice_between(&tokens[3], &tokens[3]).unwrap(), "a");
+    assert!(lex.slice_between(&tokens[6], &tokens[2]).is_err());
+}
+
+#[test]
+fn token_count_is_bounded() {
+    let mut lex = lexer("a b c d e");
+    lex.max_tokens = Some(3);
+    let err = lex.tokenize().unwrap_err();
+    assert!(format!("{:?}", err).contains("TooManyTokens(6)"));
+    assert!(lex.parser_token().is_err());
+    lex.max_tokens = Some(5);
+    lex.pos = 0;
+    assert_eq!(lex.tokenize().unwrap().len(), 5);
+    lex.parser_token().unwrap();
+    assert_eq!(lex.tokenstack.len(), 5);
+    assert_eq!(lexer("a b c d e").tokenize().unwrap().len(), 5);
+}
+
+#[test]
+fn tokens_come_with_their_position() {
+    let mut lex = lexer("ab + c\n  d\t= 1");
+    let found = lex.tokens_with_pos()
+        .map(|r| r.map(|(t, line, col)| (t.get_value().to_string(), line, col)))
+        .collect::<AstResult<Vec<_>>>().unwrap();
+    let want = [("ab", 1, 1), ("+", 1, 4), ("c", 1, 6), ("d", 2, 3), ("=", 2, 5), ("1", 2, 7)];
+    assert_eq!(found, want.iter().map(|(text, line, col)| (text.to_string(), *line, *col)).collect::<Vec<_>>());
+    let mut lex = lexer("a $ b");
+    let found = lex.tokens().collect::<Vec<_>>();
+    assert_eq!(found.len(), 2);
+    assert!(found[1].is_err());
+}
+
+#[test]
+fn empty_matches_fall_through() {
+    let mut lex = Lexer::new("12 ab 3".to_string(), DefaultHandler);
+    lex.add_regex("num", Regex::new(r"\d*").unwrap());
+    lex.add_regex("id", Regex::new("[a-z]*").unwrap());
+    assert_eq!(values(&mut lex), vec![("num", "12".to_string()), ("id", "ab".to_string()), ("num", "3".to_string())]);
+    let mut lex = Lexer::new("x".to_string(), DefaultHandler);
+    lex.add_regex("num", Regex::new(r"\d*").unwrap());
+    assert!(lex.tokenize().is_err());
+}
+
+#[test]
+fn token_types_can_be_rewritten() {
+    let mut lex = lexer("if x");
+    let tokens = lex.tokenize().unwrap();
+    let kw = tokens[0].clone().with_type("kw_if");
+    assert_eq!(kw.ty, "kw_if");
+    assert_ne!(kw, tokens[0]);
+    let mut back = kw.clone();
+    back.set_type("id");
+    assert_eq!(back, tokens[0]);
+    lex.add_keyword("if", "kw_if");
+    assert_eq!(lex.tokenize().unwrap()[0], kw);
+}
+
+#[test]
+fn priorities_order_the_rules() {
+    let mut lex = Lexer::new("if iffy".to_string(), DefaultHandler);
+    lex.add_regex("id", Regex::new("[a-z]+").unwrap());
+    lex.add_regex_prio("kw", Regex::new(r"if\b").unwrap(), 5);
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!((tokens[0].ty, tokens[0].rule_id()), ("kw", Some(1)));
+    assert_eq!(tokens[1].ty, "id");
+    // the longest match wins first, priority only breaks ties
+    lex.match_policy = MatchPolicy::LongestMatch;
+    lex.add_regex_prio("kw2", Regex::new("if").unwrap(), 9);
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!((tokens[0].ty, tokens[1].ty), ("kw2", "id"));
+}
+
+#[test]
+fn token_shapes_ignore_positions() {
+    let shape = [("lit", "("), ("id", "a"), ("lit", "+"), ("id", "b"), ("lit", ")")];
+    assert_token_shape(&lexer("(a + b)").tokenize().unwrap(), &shape);
+    let tokens = lexer("(  a+\n b )").tokenize().unwrap();
+    assert_token_shape(&tokens, &shape);
+    assert!(tokens[1].same_token("id", "a") && !tokens[1].same_token("lit", "a"));
+    let short = std::panic::AssertUnwindSafe(|| assert_token_shape(&tokens[..2], &shape));
+    assert!(std::panic::catch_unwind(short).is_err());
+}
+
+#[test]
+fn comments_can_be_tokens() {
+    let mut lex = lexer("a // note\n(b /* x\ny */ c) # gone\nd");
+    lex.add_line_comment("//", true);
+    lex.add_block_comment("/*", "*/", true);
+    lex.add_line_comment("#", false);
+    let tokens = lex.tokenize().unwrap();
+    let comments = tokens.iter().filter(|t| t.ty == "comment").collect::<Vec<_>>();
+    let found = comments.iter().map(|t| (t.start, t.end, t.get_value())).collect::<Vec<_>>();
+    assert_eq!(found, vec![(2, 9, "// note"), (13, 22, "/* x\ny */")]);
+    assert_eq!(comments[1].lineno, 2);
+    assert_eq!(tokens.len(), 8);
+    // grouping drops them
+    lex.parser_token().unwrap();
+    assert_eq!(lex.tokenstack.iter().map(|t| t.to_sexpr()).collect::<Vec<_>>(), vec!["a", "(( b c ))", "d"]);
+    let mut lex = lexer("a /* open");
+    lex.add_block_comment("/*", "*/", false);
+    assert!(lex.tokenize().is_err());
+}
+
+#[test]
+fn scanning_until_a_delimiter() {
+    let mut lex = lexer("x <<raw\ntext\nEND y");
+    lex.get_token().unwrap();
+    assert_eq!(lex.scan_until("\n", true).unwrap().get_value(), " <<raw\n");
+    let raw = lex.scan_until("END", false).unwrap();
+    assert_eq!((raw.get_value(), raw.lineno), ("text\n", 2));
+    assert_eq!(lex.get_token().unwrap().unwrap().get_value(), "END");
+    let pos = lex.pos;
+    assert!(lex.scan_until("@@", true).is_err());
+    assert_eq!(lex.pos, pos);
+    assert_eq!(lex.get_token().unwrap().unwrap().get_value(), "y");
+}
+
+#[test]
+fn literals_and_regexes_of_the_same_length() {
+    let (arrow, neg) = (Regex::new("->|==").unwrap(), Regex::new(r"-\d+").unwrap());
+    let run = |literal_priority: bool| {
+        let mut lex = lexer("a->b >= -5 ==");
+        lex.add_literal("->");
+        lex.add_literal(">=");
+        lex.add_regex("arrow", arrow.clone());
+        lex.add_regex("neg", neg.clone());
+        lex.literal_priority = literal_priority;
+        values(&mut lex)
+    };
+    let want = |arrow: &'static str| {
+        let tokens = [("id", "a"), (arrow, "->"), ("id", "b"), ("lit", ">="), ("neg", "-5"), ("arrow", "==")];
+        tokens.iter().map(|(ty, text)| (*ty, text.to_string())).collect::<Vec<_>>()
+    };
+    assert_eq!(run(true), want("lit"));
+    assert_eq!(run(false), want("arrow"));
+    let mut lex = lexer("a >= b");
+    lex.add_literal(">=");
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!(tokens.len(), 3);
+    assert!(lex.report_suspicious_splits(&tokens).is_empty());
+}
+
+#[test]
+fn tokens_stream_into_a_sink() {
+    let mut count = 0;
+    lexer("(a + b) * c").lex_into(|_| {
+        count += 1;
+        Ok(())
+    }).unwrap();
+    assert_eq!(count, 7);
+    let mut lex = lexer("a b c d");
+    let mut seen = vec![];
+    let result = lex.lex_into(|token| {
+        if token.get_value() == "c" {
+            return Err(AstError::new_unexpected_token_error(token));
+        }
+        seen.push(token.get_value().to_string());
+        Ok(())
+    });
+    assert_eq!(kind(&result.unwrap_err()), "UnexpectedToken");
+    assert_eq!(seen, vec!["a", "b"]);
+    assert_eq!(lex.get_token().unwrap().unwrap().get_value(), "d");
+}
+
+#[test]
+fn capture_groups_are_kept() {
+    let mut lex = Lexer::new("x 12u8 7i 3".to_string(), DefaultHandler);
+    lex.add_regex("id", Regex::new("[a-z]+").unwrap());
+    lex.add_regex_capturing("num", Regex::new(r"(\d+)([a-z]\d*)?").unwrap());
+    let tokens = lex.tokenize().unwrap();
+    assert!(tokens[0].captures.is_empty());
+    assert_eq!(tokens[1].captures, vec![(2, 4), (4, 6)]);
+    assert_eq!(&lex.data[tokens[1].captures[1].0..tokens[1].captures[1].1], "u8");
+    assert_eq!(tokens[2].captures, vec![(7, 8), (8, 9)]);
+    // a group that did not take part is empty at the match start
+    assert_eq!(tokens[3].captures, vec![(10, 11), (10, 10)]);
+    let back = LexToken::from_bytes(&LexToken::to_bytes(&tokens), &lex.known_types()).unwrap();
+    assert_eq!(back[1].captures, tokens[1].captures);
+}
+
+#[test]
+fn ignore_and_literals_may_not_overlap() {
+    let mut lex = lexer("a + b");
+    assert!(lex.validate_config().is_ok());
+    let err = lex.set_ignore(" \t+").unwrap_err();
+    assert!(format!("{:?}", err).contains("ConfigConflict('+')"));
+    assert_eq!(lex.ignore, " \t");
+    assert!(lex.set_literals("+- ").is_err());
+    assert!(lex.set_literals("+-").is_ok());
+    lex.add_literal("\t>");
+    assert!(lex.validate_config().is_err());
+    lex.ignore = "+";
+    assert!(lex.validate_config().is_err());
+}
+
+#[test]
+fn token_streams_diff_by_shape() {
+    let tokens = |src: &str| lexer(src).tokenize().unwrap();
+    let base = tokens("a + b");
+    assert_eq!(diff_tokens(&base, &tokens("a  *  b")), vec![TokenEdit::Replace { old: 1, new: 1 }]);
+    assert_eq!(diff_tokens(&base, &base), vec![]);
+    let wrapped = tokens("x a + b y");
+    assert_eq!(diff_tokens(&base, &wrapped), vec![TokenEdit::Insert { new: 0 }, TokenEdit::Insert { new: 4 }]);
+    assert_eq!(diff_tokens(&wrapped, &base), vec![TokenEdit::Delete { old: 0 }, TokenEdit::Delete { old: 4 }]);
+    let want = vec![TokenEdit::Replace { old: 1, new: 1 }, TokenEdit::Replace { old: 2, new: 2 }, TokenEdit::Insert { new: 3 }];
+    assert_eq!(diff_tokens(&base, &tokens("a - c d")), want);
+}
+
+#[test]
+fn ignore_predicates_skip_unicode_spaces() {
+    let mut lex = lexer("a\u{a0}b \u{2003}c\n\u{3000}d");
+    assert!(lex.tokenize().is_err());
+    lex.set_ignore_predicate(unicode_whitespace);
+    let tokens = lex.tokenize().unwrap();
+    let found = tokens.iter().map(|t| (t.get_value(), t.lineno)).collect::<Vec<_>>();
+    assert_eq!(found, vec![("a", 1), ("b", 1), ("c", 1), ("d", 2)]);
+    let mut lex = lexer("a\u{a0}\nb");
+    lex.emit_newline = true;
+    lex.set_ignore_predicate(|c| c == '\u{a0}');
+    assert_eq!(lex.tokenize().unwrap().iter().map(|t| t.ty).collect::<Vec<_>>(), vec!["id", "newline", "id"]);
+}
+
+#[test]
+fn recovery_skips_to_an_anchor() {
+    let mut lex = lexer("x = 1 $ + ; y = 2 ; z");
+    lex.add_regex("semi", Regex::new(";").unwrap());
+    lex.set_literals("+-*/=").unwrap();
+    for _ in 0..3 {
+        lex.get_token().unwrap();
+    }
+    assert_eq!(lex.skip_to_any(&["semi"]).unwrap().start, 10);
+    assert_eq!(lex.get_token().unwrap().unwrap().get_value(), "y");
+    assert_eq!(lex.skip_to_any(&["semi", "nope"]).unwrap().start, 18);
+    assert!(lex.skip_to_any(&["semi"]).is_none());
+    assert!(lex.get_token().unwrap().is_none());
+}
+
+#[test]
+fn every_default_literal_is_found() {
+    let literals = "+-*/%^<>=!?()[]{}.,;:";
+    let tokens = Lexer::new(literals.to_string(), DefaultHandler).tokenize().unwrap();
+    assert_eq!(tokens.len(), literals.len());
+    assert!(tokens.iter().all(|t| t.ty == "lit"));
+    for c in ["#", "&", "~", "|"] {
+        assert!(Lexer::new(c.to_string(), DefaultHandler).tokenize().is_err(), "{}", c);
+    }
+    // chars past ASCII miss the bitset but are still found
+    let mut lex = Lexer::new("→+a".to_string(), DefaultHandler);
+    lex.set_literals("→+").unwrap();
+    assert!(lex.tokenize().is_err());
+    let tokens = lex.tokenize_range(0, "→+".len()).unwrap();
+    assert_eq!(tokens.iter().map(|t| t.get_value()).collect::<Vec<_>>(), ["→", "+"]);
+    lex.set_literals("+").unwrap();
+    assert!(lex.tokenize_range(0, "→+".len()).is_err());
+}
+
+#[test]
+fn tokens_give_their_raw_bytes() {
+    let mut lex = Lexer::new("ab 日本 c".to_string(), DefaultHandler);
+    lex.add_regex("id", Regex::new(r"\w+").unwrap());
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!(tokens[0].bytes(), b"ab");
+    assert_eq!(tokens[1].bytes(), "日本".as_bytes());
+    let mut half = tokens[1].clone();
+    half.end = half.start + 1;
+    assert_eq!(half.bytes(), &"日".as_bytes()[..1]);
+}
+
+#[test]
+fn adjacent_tokens_join() {
+    let tokens = lexer("a >= b > = c").tokenize().unwrap();
+    let ge = tokens[1].clone().join(tokens[2].clone(), "lit").unwrap();
+    assert_eq!((ge.get_value(), ge.start, ge.end, ge.ty, ge.lineno), (">=", 2, 4, "lit", 1));
+    assert!(tokens[4].clone().join(tokens[5].clone(), "lit").is_err());
+    // an equal source in another `Arc` is the same source
+    let copy = lexer("a >= b > = c").tokenize().unwrap();
+    assert!(tokens[1].clone().join(copy[2].clone(), "op").is_ok());
+    let other = lexer("a >> b").tokenize().unwrap();
+    assert!(tokens[1].clone().join(other[2].clone(), "op").is_err());
+}
+
+#[test]
+fn tokens_before_a_position() {
+    let mut lex = lexer("a + bc");
+    let before = |lex: &mut Lexer<DefaultHandler>, pos| {
+        lex.tokens_before(pos).unwrap().iter().map(|t| t.get_value().to_string()).collect::<Vec<_>>()
+    };
+    assert_eq!(before(&mut lex, 6), ["bc", "+", "a"]);
+    assert_eq!(before(&mut lex, 4), ["+", "a"]);
+    assert_eq!(before(&mut lex, 5), ["+", "a"]);
+    assert_eq!(before(&mut lex, 3), ["+", "a"]);
+    assert!(before(&mut lex, 0).is_empty());
+    assert_eq!(lex.pos, 0);
+    assert_eq!(lex.tokenize().unwrap().len(), 3);
+}
+
+#[test]
+fn token_sequences_match_patterns() {
+    let mut lex = lexer("f(x) g + y");
+    let found = lex.match_seq(&[Pat::Type("id"), Pat::Value("lit", "(")]).unwrap();
+    assert_eq!(found.iter().map(|t| t.get_value()).collect::<Vec<_>>(), ["f", "("]);
+    assert_eq!(lex.pos, 2);
+    assert!(lex.match_seq(&[Pat::Type("id"), Pat::Value("lit", ")")]).is_some());
+    // a failed match consumes nothing
+    assert!(lex.match_seq(&[Pat::Type("id"), Pat::Value("lit", "(")]).is_none());
+    assert_eq!(lex.pos, 4);
+    assert_eq!(lex.get_token().unwrap().unwrap().get_value(), "g");
+    assert!(lex.match_seq(&[Pat::Value("lit", "+"), Pat::Type("id"), Pat::Type("id")]).is_none());
+    assert_eq!(lex.pos, 6);
+    // nor leaves anything else behind
+    let mut lex = lexer("#if X\na\n#endif\nb = 1");
+    lex.add_directive_prefix("#");
+    lex.define("X");
+    lex.trace = true;
+    lex.match_window = Some(0);
+    assert!(lex.match_seq(&[Pat::Type("id"), Pat::Type("num")]).is_none());
+    assert!(lex.take_trace().is_empty() && lex.overruns.is_empty());
+    lex.match_window = None;
+    let texts: Vec<_> = values(&mut lex).into_iter().map(|t| t.1).collect();
+    assert_eq!(texts, ["a", "b", "=", "1"]);
+    assert_eq!(lex.match_seq(&[]), Some(vec![]));
+}
+
+#[test]
+fn literals_can_be_any_chars() {
+    let mut lex = lexer(r"a \ b ' → (");
+    lex.strings.clear();
+    lex.set_literals_chars(&['\\', '\'', '→', '(']).unwrap();
+    let found = values(&mut lex).into_iter().filter(|(ty, _)| *ty == "lit").map(|(_, text)| text).collect::<Vec<_>>();
+    assert_eq!(found, ["\\", "'", "→", "("]);
+    assert!(lex.set_literals_chars(&[' ']).is_err());
+    assert_eq!(lex.literals, "\\'→(");
+    // a runtime string is kept owned and read on the next token
+    lex.set_literals(String::from("+")).unwrap();
+    lex.pos = 0;
+    assert!(lex.tokenize().is_err());
+    assert_eq!(lexer("a+b").tokenize().unwrap().len(), 3);
+}
+
+#[test]
+fn match_windows_bound_regex_matches() {
+    let mut lex = lexer("abcdefghij + 1");
+    lex.match_window = Some(10);
+    assert_eq!(lex.tokenize().unwrap()[0].get_value(), "abcdefghij");
+    assert!(lex.overruns.is_empty());
+    let mut lex = lexer("abcdefghij + 1");
+    lex.match_window = Some(9);
+    assert!(lex.tokenize().is_err());
+    assert_eq!(lex.overruns, vec![(0, 0)]);
+
+    let mut lex = Lexer::new("a".repeat(20000), DefaultHandler);
+    lex.add_regex("bang", Regex::new("a+!").unwrap());
+    lex.add_regex("one", Regex::new("a").unwrap());
+    lex.match_window = Some(32);
+    assert_eq!(lex.tokenize().unwrap().len(), 20000);
+    assert!(lex.overruns.is_empty());
+
+    let mut lex = Lexer::new("aaaaaaaaaaaaaaaaaaaa b".to_string(), DefaultHandler);
+    lex.add_regex("run", Regex::new("a+").unwrap());
+    lex.add_regex("one", Regex::new("[ab]").unwrap());
+    lex.match_window = Some(8);
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!(tokens[0].ty, "one");
+    assert!(lex.overruns.contains(&(0, 0)));
+    assert!(tokens.iter().any(|t| t.ty == "run" && t.get_value().len() <= 8));
+    assert_eq!(tokens.last().unwrap().get_value(), "b");
+}
+
+#[test]
+fn tokens_split_into_statements() {
+    let statements = |src: &str| {
+        lexer(src).tokens().statements(";")
+            .map(|s| s.map(|s| s.iter().map(|t| t.get_value().to_string()).collect::<Vec<_>>()))
+            .collect::<Vec<_>>()
+    };
+    let found = statements("a=1; f(b;c); d;").into_iter().collect::<AstResult<Vec<_>>>().unwrap();
+    assert_eq!(found, vec![vec!["a", "=", "1"], vec!["f", "(", "b", ";", "c", ")"], vec!["d"]]);
+    let found = statements("a;;b").into_iter().map(|s| s.unwrap().len()).collect::<Vec<_>>();
+    assert_eq!(found, [1, 0, 1]);
+    let found = statements("a; # b");
+    assert_eq!(found.len(), 2);
+    assert_eq!(found[0].as_ref().unwrap().len(), 1);
+    assert!(found[1].is_err());
+}
+
+#[test]
+fn identifiers_have_a_length_limit() {
+    let mut lex = lexer("short averyveryverylongname");
+    lex.keywords.insert("averyveryverylongname", "kw");
+    lex.max_identifier_len = Some(8);
+    assert_eq!(lex.tokenize().unwrap().len(), 2);
+    let mut lex = lexer("ok fine toolongname x");
+    lex.max_identifier_len = Some(8);
+    assert_eq!(lex.get_token().unwrap().unwrap().get_value(), "ok");
+    assert_eq!(lex.get_token().unwrap().unwrap().get_value(), "fine");
+    let err = lex.get_token().unwrap_err();
+    assert!(err.to_string().starts_with("Identifier too long Error"), "{err}");
+    assert_eq!(err.span(), Some((8, 19)));
+    let mut lex = lexer("日本語日本語 a");
+    lex.add_regex("id", Regex::new(r"\w+").unwrap());
+    lex.res.swap(0, 2);
+    lex.max_identifier_len = Some(6);
+    assert_eq!(lex.tokenize().unwrap().len(), 2);
+}
+
+#[test]
+fn tracing_records_each_decision() {
+    use LexEvent::*;
+    let mut lex = lexer("ab + 1\n@");
+    lex.tokenize().ok();
+    assert!(lex.take_trace().is_empty());
+    lex.trace = true;
+    assert!(lex.tokenize().is_err());
+    assert_eq!(lex.take_trace(), [
+        MatchRegex { rule: 0, start: 0, end: 2 },
+        SkipIgnore { pos: 2 },
+        MatchLiteral { start: 3, end: 4 },
+        SkipIgnore { pos: 4 },
+        MatchRegex { rule: 1, start: 5, end: 6 },
+        SkipIgnore { pos: 6 },
+        Unknown { pos: 7 },
+    ]);
+    assert!(lex.take_trace().is_empty());
+    let mut lex = lexer("x // c\n");
+    lex.add_line_comment("//", false);
+    lex.emit_newline = true;
+    lex.trace = true;
+    assert_eq!(lex.tokenize().unwrap().len(), 2);
+    assert_eq!(lex.take_trace(), [
+        MatchRegex { rule: 0, start: 0, end: 1 },
+        SkipIgnore { pos: 1 },
+        Other { ty: "comment", start: 2, end: 6 },
+        Other { ty: "newline", start: 6, end: 7 },
+    ]);
+}
+
+#[test]
+fn whitespace_can_be_tokens() {
+    let mut lex = lexer("a   b");
+    lex.emit_ws = true;
+    assert_eq!(values(&mut lex), [("id", "a".into()), ("ws", "   ".into()), ("id", "b".into())]);
+    let mut lex = lexer(" a \t b\nc ");
+    lex.emit_ws = true;
+    let found = values(&mut lex);
+    let ws: Vec<_> = found.iter().filter(|t| t.0 == "ws").map(|t| t.1.as_str()).collect();
+    assert_eq!(ws, [" ", " \t ", " "]);
+    assert_eq!(found.len(), 6);
+    let mut lex = lexer("ab");
+    lex.emit_ws = true;
+    assert_eq!(values(&mut lex).len(), 1);
+}
+
+#[test]
+fn shadowed_rules_are_reported() {
+    let mut lex = Lexer::new("abc 123 x9 + y".to_string(), DefaultHandler);
+    lex.add_regex("word", Regex::new(r"\w+").unwrap());
+    lex.add_regex("num", Regex::new(r"\d+").unwrap());
+    lex.add_regex("id", Regex::new(r"[a-z]+").unwrap());
+    assert_eq!(lex.analyze_rules(), [
+        RuleWarning { rule: 1, shadowed_by: vec![Some(0)] },
+        RuleWarning { rule: 2, shadowed_by: vec![Some(0)] },
+    ]);
+    let mut lex = lexer("abc 123 x9 + y");
+    assert!(lex.analyze_rules().is_empty());
+    lex.add_regex("plus", Regex::new(r"\+").unwrap());
+    assert_eq!(lex.analyze_rules(), [RuleWarning { rule: 2, shadowed_by: vec![None] }]);
+    lex.literal_priority = false;
+    assert!(lex.analyze_rules().is_empty());
+    let mut lex = Lexer::new("abc 123".to_string(), DefaultHandler);
+    lex.add_regex("word", Regex::new(r"\w+").unwrap());
+    lex.add_regex_prio("num", Regex::new(r"\d+").unwrap(), 5);
+    assert!(lex.analyze_rules().is_empty());
+}
+
+#[test]
+fn rules_can_have_runtime_type_names() {
+    let config = [("color", r"#[0-9a-f]{6}"), ("unit", r"\d+px")];
+    let mut lex = Lexer::new("#ff00aa 12px #000000".to_string(), DefaultHandler);
+    let mut names = vec![];
+    for (name, re) in config {
+        names.push(lex.add_regex_named(&format!("css_{}", name), Regex::new(re).unwrap()));
+    }
+    let tokens = lex.tokenize().unwrap();
+    let found: Vec<_> = tokens.iter().map(|t| t.type_name()).collect();
+    assert_eq!(found, ["css_color", "css_unit", "css_color"]);
+    assert!(tokens.iter().all(|t| t.ty == "named"));
+    let name = |i: usize| tokens[i].name.clone().unwrap();
+    assert!(std::sync::Arc::ptr_eq(&name(0), &names[0]) && std::sync::Arc::ptr_eq(&name(2), &names[0]));
+    assert!(std::sync::Arc::ptr_eq(&lex.types.intern("css_unit"), &name(1)));
+    assert_eq!(lex.types.len(), 2);
+    assert_eq!(lex.types.get("css_never_made"), None);
+    assert_ne!(tokens[0], tokens[1].clone_base_token());
+
+    let bytes = LexToken::to_bytes(&tokens);
+    let back = LexToken::from_bytes_named(&bytes, &lex.known_types(), &lex.types).unwrap();
+    assert_eq!(back, tokens);
+    assert!(std::sync::Arc::ptr_eq(back[1].name.as_ref().unwrap(), &names[1]));
+    assert!(LexToken::from_bytes(&bytes, &lex.known_types()).is_err());
+    let other = Lexer::new(String::new(), DefaultHandler);
+    assert_eq!(other.types.get("css_color"), None);
+}
+
+#[test]
+fn large_sources_keep_line_numbers() {
+    let n = 50_000;
+    let mut lex = lexer(&"a = 1 +\n".repeat(n));
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!(tokens.len(), 4 * n);
+    assert_eq!(tokens.last().unwrap().lineno, n);
+    assert!(tokens.windows(2).all(|w| w[0].lineno <= w[1].lineno));
+    let mut lex = lexer("a\nb\nc\nd");
+    assert_eq!(lex.tokenize().unwrap().last().unwrap().lineno, 4);
+    let back: Vec<_> = lex.tokenize_range(2, 3).unwrap().iter().map(|t| t.lineno).collect();
+    assert_eq!(back, [2]);
+    assert_eq!(lex.get_now_lineno(0), 1);
+    assert_eq!(lex.get_now_lineno(6), 4);
+    let digits = Regex::new(r"\d+").unwrap();
+    let mut lex = Lexer::new(format!("{} 7", "x ".repeat(n)), DefaultHandler);
+    lex.add_regex("num", digits);
+    lex.add_regex("x", Regex::new("x").unwrap());
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!((tokens.len(), tokens[n].ty), (n + 1, "num"));
+}
+
+#[test]
+fn runtime_types_are_told_apart() {
+    let src = "#ff00aa 12px #000000";
+    let named = || {
+        let mut lex = Lexer::new(src.to_string(), DefaultHandler);
+        lex.add_regex_named("css_color", Regex::new(r"#[0-9a-f]{6}").unwrap());
+        lex.add_regex_named("css_unit", Regex::new(r"\d+px").unwrap());
+        lex
+    };
+    let tokens = named().tokenize().unwrap();
+    let counts = type_histogram(&tokens);
+    assert_eq!((counts.len(), counts.get("css_color"), counts.get("css_unit")), (2, Some(&2), Some(&1)));
+    assert!(tokens[1].same_token("css_unit", "12px") && !tokens[1].same_token("named", "12px"));
+    assert_token_shape(&tokens, &[("css_color", "#ff00aa"), ("css_unit", "12px"), ("css_color", "#000000")]);
+    let mut group = tokens[1].clone();
+    group.subs = vec![tokens[0].clone(), tokens[2].clone()];
+    assert_eq!(group.collect_type("css_color").len(), 2);
+    assert!(group.collect_type("named").is_empty());
+    let mut lex = named();
+    assert!(lex.match_seq(&[Pat::Type("css_color"), Pat::Type("css_color")]).is_none());
+    assert!(lex.match_seq(&[Pat::Type("css_color"), Pat::Value("css_unit", "12px")]).is_some());
+    let mut lex = named();
+    assert_eq!(lex.skip_to_any(&["css_unit"]).unwrap().get_value(), "12px");
+    let mut other = Lexer::new(src.to_string(), DefaultHandler);
+    other.add_regex_named("css_unit", Regex::new(r"#[0-9a-f]{6}").unwrap());
+    other.add_regex_named("css_color", Regex::new(r"\d+px").unwrap());
+    assert_eq!(diff_tokens(&tokens, &other.tokenize().unwrap()).len(), 3);
+}
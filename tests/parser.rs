@@ -0,0 +1,700 @@
+mod common;
+
+use common::*;
+use lang_ast::*;
+use regex::Regex;
+
+#[test]
+fn evaluates_list_groups() {
+    match calc("[10, 20, 30]").eval().unwrap() {
+        AstAny::List(items) => assert_eq!(items.iter().map(int).collect::<Vec<_>>(), vec![10, 20, 30]),
+        other => panic!("not a list: {:?}", other),
+    }
+    match calc("[1, [2]]").eval().unwrap() {
+        AstAny::List(items) => assert_eq!(items[1], AstAny::List(vec![AstAny::I64(2)])),
+        other => panic!("not a list: {:?}", other),
+    }
+}
+
+#[test]
+fn evaluates_by_precedence() {
+    assert_eq!(int(&calc("1 + 2*-3").eval().unwrap()), -5);
+    assert_eq!(int(&calc("10 - 2 - 3").eval().unwrap()), 5);
+    assert_eq!(int(&calc("(1 + 2) * 3").eval().unwrap()), 9);
+    assert!(calc("1 +").eval().is_err());
+    assert!(calc("()").eval().is_err());
+}
+
+#[test]
+fn only_registered_operators_are_prefix() {
+    assert_eq!(tree(&mut calc("- - 1")).to_sexpr(), "(- (- 1))");
+    assert_eq!(kind(&calc("* 1").eval().unwrap_err()), "UnexpectedToken");
+    let mut lex = calc("+ 1");
+    lex.add_prefix_operator("lit", "+");
+    assert_eq!(tree(&mut lex).to_sexpr(), "(+ 1)");
+}
+
+#[test]
+fn evaluates_ternary() {
+    assert_eq!(int(&calc("1 ? 2 : 3").eval().unwrap()), 2);
+    assert_eq!(int(&calc("0 ? 2 : 3").eval().unwrap()), 3);
+    assert_eq!(int(&calc("0 ? 2 : 0 ? 4 : 5").eval().unwrap()), 5);
+    assert_eq!(int(&calc("0 ? 2 : 3 + 4").eval().unwrap()), 7);
+    let nested = tree(&mut calc("a ? b : c ? d : e"));
+    assert_eq!(nested.to_sexpr(), "(? a b (? c d e))");
+    assert!(calc("1 ? 2").eval().is_err());
+    assert!(calc("1 ? 2 3").eval().is_err());
+}
+
+#[test]
+fn evaluates_each_statement() {
+    let found = calc("1 + 1; 2 * 3;").eval_all().unwrap();
+    assert_eq!(found, vec![AstAny::I64(2), AstAny::I64(6)]);
+}
+
+#[test]
+fn folds_constant_subtrees() {
+    let mut lex = calc("1 + 2 * 3");
+    let expr = tree(&mut lex);
+    let folded = lex.fold_constants(expr).unwrap();
+    assert_eq!((folded.ty, folded.start, folded.end, folded.subs.len()), ("num", 0, 9, 0));
+    assert_eq!(folded.value, AstAny::I64(7));
+    let mut lex = calc("a + (2 * 3)");
+    let expr = tree(&mut lex);
+    let folded = lex.fold_constants(expr).unwrap();
+    assert_eq!(folded.subs[0].get_value(), "a");
+    assert_eq!((folded.subs[1].get_value(), &folded.subs[1].value), ("2 * 3", &AstAny::I64(6)));
+    let src = vec!["1"; 10_000].join(" + ");
+    let mut lex = calc(&src);
+    let expr = tree(&mut lex);
+    let folded = lex.fold_constants(expr).unwrap();
+    assert_eq!((folded.start, folded.end, &folded.value), (0, src.len(), &AstAny::I64(10_000)));
+    let mut lex = calc(&format!("a * ({})", src));
+    let expr = tree(&mut lex);
+    let folded = lex.fold_constants(expr).unwrap();
+    assert_eq!((folded.subs[1].subs.len(), &folded.subs[1].value), (0, &AstAny::I64(10_000)));
+}
+
+#[test]
+fn renders_trees_as_sexpr() {
+    assert_eq!(tree(&mut calc("1 + 2 * 3")).to_sexpr(), "(+ 1 (* 2 3))");
+    assert_eq!(tree(&mut calc("-(1 - 2) - 3 ? [a, b] : c")).to_sexpr(), "(? (- (- (- 1 2)) 3) ([ a b) c)");
+    let n = 10_000;
+    let sexpr = tree(&mut calc(&vec!["1"; n].join(" + "))).to_sexpr();
+    assert_eq!(sexpr, format!("{}1{}", "(+ ".repeat(n - 1), " 1)".repeat(n - 1)));
+}
+
+#[test]
+fn deep_nesting_fails_cleanly() {
+    let n = 5000;
+    let src = format!("{}1{}", "(".repeat(n), ")".repeat(n));
+    assert_eq!(kind(&calc(&src).eval().unwrap_err()), "TooDeep");
+    let src = format!("{}1", "-".repeat(n));
+    assert_eq!(kind(&calc(&src).eval().unwrap_err()), "TooDeep");
+    let src = format!("{}1{}", "(".repeat(60), ")".repeat(60));
+    assert_eq!(int(&calc(&src).eval().unwrap()), 1);
+    let mut lex = calc("- - 1");
+    lex.max_depth = 1;
+    assert!(lex.eval().is_err());
+}
+
+#[test]
+fn long_flat_chains_are_not_nesting() {
+    for n in [60, 100, 10_000] {
+        let src = vec!["1"; n].join(" + ");
+        assert_eq!(int(&calc(&src).eval().unwrap()), n as i64);
+    }
+    let src = vec!["2 * 3"; 1000].join(" - ");
+    assert_eq!(int(&calc(&src).eval().unwrap()), 6 - 6 * 999);
+    let mut lex = calc(&vec!["1"; 100].join(" + "));
+    assert!(lex.eval_budget(150).is_err());
+    // built, copied and dropped without a frame per level
+    let src = vec!["1"; 100_000].join(" + ");
+    let chain = tree(&mut calc(&src));
+    assert_eq!(chain.full_span(), (0, src.len()));
+    let copy = chain.clone();
+    assert!(copy == chain);
+    drop(chain);
+    assert_eq!(copy.full_span(), (0, src.len()));
+}
+
+#[test]
+fn long_flat_chains_convert_to_exprs() {
+    let src = vec!["x"; 10_000].join(" + ");
+    let mut expr = lexer(&src).parse_expr_typed().unwrap();
+    assert_eq!(expr.span(), (0, src.len()));
+    let mut depth = 0;
+    while let Expr::Binary { lhs, rhs, span, .. } = expr {
+        assert_eq!((span.0, rhs.span().1), (0, span.1));
+        depth += 1;
+        expr = *lhs;
+    }
+    assert_eq!((depth, expr), (9_999, Expr::Ident { name: "x".into(), span: (0, 1) }));
+    let Expr::Binary { rhs, span, .. } = lexer("a * (b + c)").parse_expr_typed().unwrap() else { panic!() };
+    assert_eq!((span, rhs.span()), ((0, 10), (5, 10)));
+}
+
+/// reads each leaf as its `line:col`
+struct Positions;
+
+impl Handler for Positions {
+    fn on_read_ctx(&mut self, token: &mut LexToken, ctx: &LexerCtx) -> AstResult<AstAny> {
+        assert!(ctx.precedence("lit", "*").is_some());
+        let (line, col) = ctx.line_col(token.start);
+        Ok(AstAny::Str(format!("{}:{}", line, col)))
+    }
+
+    fn on_binary(&mut self, _op: &LexToken, lhs: AstAny, rhs: AstAny) -> AstResult<AstAny> {
+        Ok(AstAny::List(vec![lhs, rhs]))
+    }
+}
+
+#[test]
+fn handlers_see_the_lexer_context() {
+    let mut lex = Lexer::new("a +\n  é + b".to_string(), Positions);
+    lex.add_regex("id", Regex::new(r"\w+").unwrap());
+    let pos = |s: &str| AstAny::Str(s.to_string());
+    let want = AstAny::List(vec![AstAny::List(vec![pos("1:1"), pos("2:3")]), pos("2:7")]);
+    assert_eq!(lex.eval().unwrap(), want);
+}
+
+/// `Calc` with variables kept by the handler
+#[derive(Default)]
+struct Env(std::collections::HashMap<String, AstAny>);
+
+impl Handler for Env {
+    fn on_read(&mut self, token: &mut LexToken) -> AstResult<AstAny> {
+        match token.ty {
+            "num" => Calc.on_read(token),
+            _ => self.0.get(token.get_value()).cloned().ok_or_else(|| AstError::new_undefined_error(token.clone_base_token())),
+        }
+    }
+
+    fn on_binary(&mut self, op: &LexToken, lhs: AstAny, rhs: AstAny) -> AstResult<AstAny> {
+        Calc.on_binary(op, lhs, rhs)
+    }
+
+    fn on_assign(&mut self, name: &LexToken, value: AstAny) -> AstResult<AstAny> {
+        self.0.insert(name.get_value().to_string(), value.clone());
+        Ok(value)
+    }
+}
+
+#[test]
+fn assignments_go_to_the_handler() {
+    let env = |src: &str| {
+        let mut lex = Lexer::new(src.to_string(), Env::default());
+        lex.add_regex("id", Regex::new("[a-z]+").unwrap());
+        lex.add_regex("num", Regex::new(r"\d+").unwrap());
+        lex.eval_all()
+    };
+    assert_eq!(env("x = 5; x + 1").unwrap(), vec![AstAny::I64(5), AstAny::I64(6)]);
+    assert_eq!(env("x = y = 2; x * y").unwrap(), vec![AstAny::I64(2), AstAny::I64(4)]);
+    assert_eq!(kind(&env("z + 1").unwrap_err()), "Undefined");
+    assert!(env("1 + x = 2").is_err());
+}
+
+#[test]
+fn missing_closers_can_be_recovered() {
+    assert!(lexer("(a + (b").parser_token().is_err());
+    let mut lex = lexer("(a + (b");
+    lex.recover_brackets = true;
+    lex.parser_token().unwrap();
+    assert_eq!(lex.tokenstack.len(), 1);
+    let outer = &lex.tokenstack[0];
+    assert_eq!(outer.to_sexpr(), "(( a + (( b ) )");
+    assert!(outer.subs[2].subs[1].synthetic && outer.subs[3].synthetic && !outer.subs[2].subs[0].synthetic);
+    assert_eq!(lex.diagnostics.iter().map(|t| t.start).collect::<Vec<_>>(), vec![5, 0]);
+    lex.pos = 0;
+    lex.parser_token().unwrap();
+    assert_eq!(lex.diagnostics.iter().map(|t| t.start).collect::<Vec<_>>(), vec![5, 0]);
+    let mut again = lexer("(a) + [b");
+    again.recover_brackets = true;
+    again.parser_token().unwrap();
+    assert_eq!(again.diagnostics.iter().map(|t| t.get_value()).collect::<Vec<_>>(), ["["]);
+    let balanced = lexer("(a) + [b]").tokenize().unwrap();
+    again.group(balanced).unwrap();
+    assert!(again.diagnostics.is_empty());
+    let tokens = lex.tokenstack.clone();
+    assert_eq!(LexToken::from_bytes(&LexToken::to_bytes(&tokens), &lex.known_types()).unwrap(), tokens);
+
+    let mut lex = Lexer::new("(1 + (2".to_string(), Calc);
+    lex.add_regex("num", Regex::new(r"\d+").unwrap());
+    lex.recover_brackets = true;
+    assert_eq!(lex.eval().unwrap(), AstAny::I64(3));
+}
+
+#[test]
+fn openers_accept_several_closers() {
+    let mut lex = lexer("do a end do b done (c)");
+    lex.add_hash_match("id", "do", "end");
+    lex.add_hash_closer("id", "do", "done");
+    lex.parser_token().unwrap();
+    let found = lex.tokenstack.iter().map(|t| t.to_sexpr()).collect::<Vec<_>>();
+    assert_eq!(found, vec!["(do a end)", "(do b done)", "(( c ))"]);
+    assert!(lex.tokenstack[1].subs[1].is_close_bracket(&lex));
+    let mut lex = lexer("do a )");
+    lex.add_hash_match("id", "do", "end");
+    assert!(lex.parser_token().is_err());
+}
+
+#[test]
+fn lexing_and_grouping_are_separate_phases() {
+    let src = "f(a, [b + {c}]) - (d)";
+    let mut lex = lexer(src);
+    let flat = lex.lex().unwrap();
+    assert_eq!(flat.len(), 16);
+    assert!(flat.iter().all(|t| t.subs.is_empty()));
+    let mut whole = lexer(src);
+    whole.parser_token().unwrap();
+    assert_eq!(lex.group(flat.clone()).unwrap(), whole.tokenstack);
+    // grouping does not read the lexer's own source
+    let mut other = lexer("");
+    assert_eq!(other.group(flat).unwrap(), whole.tokenstack);
+    assert!(other.group(lex.tokenize().unwrap()[..5].to_vec()).is_err());
+}
+
+#[test]
+fn sources_validate_without_a_handler() {
+    let rules = || vec![LexRegex::new("id", Regex::new("[a-z]+").unwrap()), LexRegex::new("num", Regex::new(r"\d+").unwrap())];
+    assert!(Lexer::validate_source("f(a, [1 + b])", rules()).is_ok());
+    assert!(Lexer::validate_source("f(a, [1 + b)", rules()).is_err());
+    assert!(Lexer::validate_source("f(a) $", rules()).is_err());
+}
+
+#[test]
+fn aliases_parse_as_their_operator() {
+    let calc = |src: &str| {
+        let mut lex = calc(src);
+        lex.add_operator_alias("plus", "+");
+        lex.add_operator_alias("times", "*");
+        lex.add_operator_alias("neg", "-");
+        lex
+    };
+    assert_eq!(tree(&mut calc("1 + 2 * 3")).to_sexpr(), "(+ 1 (* 2 3))");
+    let expr = tree(&mut calc("1 plus 2 times 3"));
+    assert_eq!(expr.to_sexpr(), "(plus 1 (times 2 3))");
+    assert_eq!(expr.op_text(), "+");
+    assert_eq!(int(&calc("1 plus 2 times 3").eval().unwrap()), 7);
+    assert_eq!(int(&calc("2 times 3 plus neg 1").eval().unwrap()), 5);
+}
+
+#[test]
+fn capacity_hints_presize_buffers() {
+    let src = "f(a, [b + {c}]) - (d)";
+    let mut hinted = lexer(src);
+    hinted.with_capacity_hint(1000);
+    assert!(hinted.tokenstack.capacity() >= 1000);
+    assert!(hinted.tokenize().unwrap().capacity() >= 1000);
+    hinted.parser_token().unwrap();
+    assert!(hinted.tokenstack.capacity() >= 1000);
+    let mut plain = lexer(src);
+    plain.parser_token().unwrap();
+    assert_eq!(hinted.tokenstack, plain.tokenstack);
+}
+
+/// adds numbers and picks the branch of `if _ then _ else _`
+struct Branches;
+
+impl Handler for Branches {
+    fn on_read(&mut self, token: &mut LexToken) -> AstResult<AstAny> {
+        Calc.on_read(token)
+    }
+
+    fn on_binary(&mut self, op: &LexToken, lhs: AstAny, rhs: AstAny) -> AstResult<AstAny> {
+        Calc.on_binary(op, lhs, rhs)
+    }
+
+    fn on_mixfix(&mut self, name: &'static str, mut parts: Vec<AstAny>) -> AstResult<AstAny> {
+        assert_eq!(name, "if");
+        let other = parts.pop().unwrap();
+        let then = parts.pop().unwrap();
+        Ok(if parts[0].is_truthy() { then } else { other })
+    }
+}
+
+#[test]
+fn mixfix_operators_take_their_parts() {
+    let branches = |src: &str| {
+        let mut lex = Lexer::new(src.to_string(), Branches);
+        lex.add_regex("kw", Regex::new("if|then|else").unwrap());
+        lex.add_regex("num", Regex::new(r"\d+").unwrap());
+        lex.add_regex("id", Regex::new("[a-z]").unwrap());
+        lex.add_mixfix("if", vec!["if", "then", "else"], 0);
+        lex
+    };
+    assert_eq!(tree(&mut branches("if a then b else c")).to_sexpr(), "(if a b c)");
+    assert_eq!(int(&branches("if 0 then 1 else 2 + 3").eval().unwrap()), 5);
+    assert_eq!(int(&branches("1 + if 1 then 7 else 2").eval().unwrap()), 8);
+    assert_eq!(kind(&branches("if 1 then 2").eval().unwrap_err()), "NoMatchClose");
+}
+
+#[test]
+fn tokens_are_retyped_by_their_neighbours() {
+    let mut lex = lexer("a / b = / x (/ y)");
+    lex.parser_token().unwrap();
+    lex.contextual_rewrite(|prev, cur, _next| {
+        if cur.get_value() == "/" && prev.is_none_or(|p| p.ty == "lit") {
+            cur.set_type("regex_start");
+        }
+    });
+    let mut types = vec![];
+    let mut stack = lex.tokenstack.iter().rev().collect::<Vec<_>>();
+    while let Some(token) = stack.pop() {
+        if token.get_value() == "/" {
+            types.push(token.ty);
+        }
+        stack.extend(token.subs.iter().rev());
+    }
+    assert_eq!(types, vec!["lit", "regex_start", "regex_start"]);
+}
+
+/// `!` is logical not before its operand, factorial after it and `a * 100 + b` between two
+struct Bang;
+
+impl Handler for Bang {
+    fn on_read(&mut self, token: &mut LexToken) -> AstResult<AstAny> {
+        Calc.on_read(token)
+    }
+
+    fn on_unary(&mut self, op: &LexToken, value: AstAny) -> AstResult<AstAny> {
+        match (op.get_value(), value) {
+            ("!", AstAny::I64(v)) => Ok(AstAny::I64((v == 0) as i64)),
+            (_, value) => Calc.on_unary(op, value),
+        }
+    }
+
+    fn on_postfix(&mut self, op: &LexToken, value: AstAny) -> AstResult<AstAny> {
+        match (op.get_value(), value) {
+            ("!", AstAny::I64(v)) => Ok(AstAny::I64((1..=v).product())),
+            _ => Ok(AstAny::Unsport),
+        }
+    }
+
+    fn on_binary(&mut self, op: &LexToken, lhs: AstAny, rhs: AstAny) -> AstResult<AstAny> {
+        match (op.get_value(), lhs, rhs) {
+            ("!", AstAny::I64(a), AstAny::I64(b)) => Ok(AstAny::I64(a * 100 + b)),
+            (_, lhs, rhs) => Calc.on_binary(op, lhs, rhs),
+        }
+    }
+}
+
+#[test]
+fn fixities_decide_where_operators_stand() {
+    let bang = |src: &str| {
+        let mut lex = Lexer::new(src.to_string(), Bang);
+        lex.add_regex("id", Regex::new("[a-z]").unwrap());
+        lex.add_regex("num", Regex::new(r"\d+").unwrap());
+        lex.set_precs(vec![
+            LexPrec::new("lit", true, vec!["+", "-"]),
+            LexPrec::new("lit", false, vec!["-", "!"]),
+            LexPrec::new("lit", true, vec!["!"]),
+        ]);
+        lex.set_fixity("lit", "-", true, true, false);
+        lex.set_fixity("lit", "!", true, false, true);
+        lex
+    };
+    let sexpr = |src: &str| tree(&mut bang(src)).to_sexpr();
+    assert_eq!(sexpr("-a"), "(- a)");
+    assert_eq!(sexpr("a-b"), "(- a b)");
+    assert_eq!(sexpr("a - -b"), "(- a (- b))");
+    assert_eq!(sexpr("a!"), "(! a)");
+    assert_eq!(sexpr("-a! + b"), "(+ (- (! a)) b)");
+    assert_eq!(sexpr("!a"), "(! a)");
+    assert_eq!(int(&bang("3! - -2").eval().unwrap()), 8);
+    assert_eq!(int(&bang("!0 + 4!").eval().unwrap()), 25);
+    let mut lex = bang("a ! b");
+    lex.parser_token().unwrap();
+    assert!(lex.parse_expr(&lex.tokenstack).is_err());
+    for (src, want) in [("3 ! 4", 304), ("3 !", 6)] {
+        let mut lex = bang(src);
+        lex.set_fixity("lit", "!", true, true, true);
+        assert_eq!(int(&lex.eval().unwrap()), want);
+    }
+}
+
+#[test]
+fn budgets_bound_handler_calls() {
+    let big = vec!["1"; 40].join(" + ");
+    assert_eq!(kind(&calc(&big).eval_budget(50).unwrap_err()), "BudgetExceeded");
+    assert_eq!(int(&calc(&big).eval().unwrap()), 40);
+    assert_eq!(int(&calc("1 + 2 * 3").eval_budget(5).unwrap()), 7);
+    assert!(calc("1 + 2 * 3").eval_budget(4).is_err());
+}
+
+#[test]
+fn precedence_tables_dump_by_level() {
+    let want = "0 right: =\n1 right: ?\n2 left: + - (also 4)\n3 left: * /\n4 right: - (also 2)\nprefix 4: -";
+    assert_eq!(lexer("").dump_precedence(), want);
+    let mut lex = lexer("");
+    lex.set_precs(vec![LexPrec::new("id", true, vec!["or"]), LexPrec::new("lit", true, vec!["*", "+"])]);
+    assert_eq!(lex.dump_precedence(), "0 left: id:or\n1 left: * +");
+    lex.set_precs(vec![LexPrec::new("lit", true, vec!["+", "*"]), LexPrec::new("lit", false, vec!["*", "*"])]);
+    lex.add_prefix_operator("lit", "*");
+    lex.add_prefix_operator("lit", "+");
+    let want = "0 left: + * (also 1 1)\n1 right: * (also 0 1) * (also 0 1)\nprefix 0: +\nprefix 1: *";
+    assert_eq!(lex.dump_precedence(), want);
+}
+
+#[test]
+fn implicitly_closed_groups_are_marked() {
+    let mut lex = lexer("(a + (b) [c");
+    lex.recover_brackets = true;
+    lex.parser_token().unwrap();
+    let outer = &lex.tokenstack[0];
+    assert!(outer.closed_implicitly);
+    assert!(!outer.subs[2].closed_implicitly);
+    assert!(outer.subs[3].closed_implicitly);
+    let back = LexToken::from_bytes(&LexToken::to_bytes(&lex.tokenstack), &lex.known_types()).unwrap();
+    assert!(back[0].closed_implicitly && !back[0].subs[2].closed_implicitly && back[0].subs[3].subs[1].synthetic);
+    let mut lex = lexer("(a)");
+    lex.parser_token().unwrap();
+    assert!(!lex.tokenstack[0].closed_implicitly);
+}
+
+#[test]
+fn fed_lines_wait_for_their_closers() {
+    let mut lex = lexer("");
+    let first = lex.feed_line("x = f(a,").unwrap();
+    assert_eq!(first.iter().map(|t| t.get_value()).collect::<Vec<_>>(), ["x", "=", "f"]);
+    assert_eq!(lex.wait_token.len(), 1);
+    let second = lex.feed_line("  b) + 1").unwrap();
+    assert_eq!(lex.wait_token.len(), 0);
+    let group = &second[0];
+    assert_eq!((group.get_value(), group.lineno, group.start), ("(", 1, 5));
+    let inner = group.subs.iter().map(|t| (t.get_value(), t.lineno, t.start)).collect::<Vec<_>>();
+    assert_eq!(inner, [("a", 1, 6), (",", 1, 7), ("b", 2, 2), (")", 2, 3)]);
+    assert_eq!(group.subs[2].data.as_str(), "  b) + 1\n");
+    assert_eq!(second[1].get_value(), "+");
+    assert_eq!((second[2].get_value(), second[2].lineno), ("1", 2));
+    assert_eq!(lex.data.as_str(), "x = f(a,\n  b) + 1\n");
+    assert!(lex.feed_line("").unwrap().is_empty());
+}
+
+#[test]
+fn many_fed_lines_keep_growing_in_place() {
+    let n = 20_000;
+    let mut lex = lexer("");
+    let mut kept = vec![];
+    for i in 0..n {
+        let line = if i % 2 == 0 { "x = (a +" } else { "  b) * 2" };
+        kept.extend(lex.feed_line(line).unwrap());
+        assert_eq!(std::sync::Arc::strong_count(&lex.data), 1);
+    }
+    assert_eq!(kept.len(), 3 * n / 2 + n / 2 * 2);
+    let last = kept.last().unwrap();
+    assert_eq!((last.get_value(), last.lineno, last.start), ("2", n, 7));
+    let group = &kept[kept.len() - 3];
+    assert_eq!((group.get_value(), group.lineno, group.subs.len()), ("(", n - 1, 4));
+    assert_eq!(lex.data.len(), n * 9);
+}
+
+/// drops newlines, counting them, renames `y` and fails on `@`
+struct NoNewlines(usize);
+
+impl Handler for NoNewlines {
+    fn on_token(&mut self, token: LexToken) -> AstResult<Option<LexToken>> {
+        if token.ty == "newline" {
+            self.0 += 1;
+            return Ok(None);
+        }
+        if token.get_value() == "@" {
+            return Err(AstError::new_unknown_char_error('@', token.start));
+        }
+        Ok(Some(if token.get_value() == "y" { token.with_type("why") } else { token }))
+    }
+}
+
+#[test]
+fn handlers_see_tokens_before_grouping() {
+    let lexer = |src: &str| {
+        let mut lex = Lexer::new(src.to_string(), NoNewlines(0));
+        lex.add_regex("id", Regex::new("[a-z]+").unwrap());
+        lex.set_literals("()@").unwrap();
+        lex.emit_newline = true;
+        lex
+    };
+    let mut lex = lexer("(a\nb)\ny\n");
+    lex.parser_token().unwrap();
+    assert_eq!(lex.handler.0, 3);
+    let mut stack = lex.tokenstack.iter().collect::<Vec<_>>();
+    while let Some(token) = stack.pop() {
+        assert_ne!(token.ty, "newline");
+        stack.extend(token.subs.iter());
+    }
+    assert_eq!(lex.tokenstack.len(), 2);
+    assert_eq!(lex.tokenstack[1].ty, "why");
+    assert_eq!(kind(&lexer("a @").parser_token().unwrap_err()), "UnknownChar");
+}
+
+#[test]
+fn colons_take_the_configured_role() {
+    let sexpr = |src: &str, role: ColonRole| {
+        let mut lex = lexer(src);
+        lex.colon_role = role;
+        lex.parser_token().unwrap();
+        lex.parse_expr(&lex.tokenstack).map(|e| e.to_sexpr())
+    };
+    assert_eq!(sexpr("c ? a : b", ColonRole::Ternary).unwrap(), "(? c a b)");
+    assert!(sexpr("x: int", ColonRole::Ternary).is_err());
+    assert_eq!(sexpr("x: int = 3", ColonRole::Annotation).unwrap(), "(= (: x int) 3)");
+    assert_eq!(sexpr("a + b: int * 2", ColonRole::Annotation).unwrap(), "(+ a (* (: b int) 2))");
+    assert_eq!(sexpr("c ? a : b", ColonRole::Annotation).unwrap(), "(? c (: a b))");
+    assert!(sexpr("x: int", ColonRole::None).is_err());
+    assert_eq!(sexpr("c ? a", ColonRole::None).unwrap(), "(? c a)");
+}
+
+#[test]
+fn typed_expressions() {
+    let num = |text: &str, at: usize| Expr::Num { text: text.to_string(), span: (at, at + text.len()) };
+    let ident = |name: &str, at: usize| Expr::Ident { name: name.to_string(), span: (at, at + name.len()) };
+    let product = Expr::Binary { op: "*".into(), lhs: Box::new(num("2", 4)), rhs: Box::new(num("3", 8)), span: (4, 9) };
+    let want = Expr::Binary { op: "+".into(), lhs: Box::new(num("1", 0)), rhs: Box::new(product), span: (0, 9) };
+    assert_eq!(lexer("1 + 2 * 3").parse_expr_typed().unwrap(), want);
+    let want = Expr::Unary { op: "-".into(), operand: Box::new(ident("a", 1)), postfix: false, span: (0, 2) };
+    assert_eq!(lexer("-a").parse_expr_typed().unwrap(), want);
+    let Expr::Binary { lhs, rhs, span, .. } = lexer("(1 + x) * f(2, y)").parse_expr_typed().unwrap() else { panic!() };
+    assert!(matches!(*lhs, Expr::Group(ref inner) if matches!(**inner, Expr::Binary { .. })));
+    assert_eq!(lhs.span(), (1, 6));
+    assert_eq!(*rhs, Expr::Call { callee: Box::new(ident("f", 10)), args: vec![num("2", 12), ident("y", 15)], span: (10, 16) });
+    assert_eq!(span, (1, 16));
+    let Expr::Ternary { then, other, .. } = lexer("c ? [1, 2] : g()").parse_expr_typed().unwrap() else { panic!() };
+    assert!(matches!(*then, Expr::List { ref items, .. } if items.len() == 2));
+    assert!(matches!(*other, Expr::Call { ref args, .. } if args.is_empty()));
+}
+
+/// `sum(...)` adds its arguments
+struct Sum;
+
+impl Handler for Sum {
+    fn on_read(&mut self, token: &mut LexToken) -> AstResult<AstAny> {
+        Calc.on_read(token)
+    }
+
+    fn on_binary(&mut self, op: &LexToken, lhs: AstAny, rhs: AstAny) -> AstResult<AstAny> {
+        Calc.on_binary(op, lhs, rhs)
+    }
+
+    fn on_call(&mut self, callee: &LexToken, args: Vec<AstAny>) -> AstResult<AstAny> {
+        assert_eq!(callee.get_value(), "sum");
+        Ok(AstAny::I64(args.iter().map(int).sum()))
+    }
+}
+
+#[test]
+fn calls_go_to_the_handler() {
+    let mut lex = Lexer::new("sum(1, 2, 3 + 4) + 1".to_string(), Sum);
+    lex.add_regex("id", Regex::new("[a-z]+").unwrap());
+    lex.add_regex("num", Regex::new(r"\d+").unwrap());
+    assert_eq!(int(&lex.eval().unwrap()), 11);
+}
+
+/// `to_rpn` output as text, a prefix operator carries a `u`
+fn rpn(src: &str) -> AstResult<String> {
+    let mut lex = lexer(src);
+    let tokens = lex.tokenize().unwrap();
+    let rpn = lex.to_rpn(&tokens)?;
+    let text = |t: &LexToken| format!("{}{}", t.get_value(), if t.has_flag(LexToken::FLAG_PREFIX) { "u" } else { "" });
+    Ok(rpn.iter().map(text).collect::<Vec<_>>().join(" "))
+}
+
+#[test]
+fn shunting_yard_orders_operators() {
+    assert_eq!(rpn("1 + 2 * 3").unwrap(), "1 2 3 * +");
+    assert_eq!(rpn("(1 + 2) * 3").unwrap(), "1 2 + 3 *");
+    assert_eq!(rpn("1 - 2 - 3").unwrap(), "1 2 - 3 -");
+    assert_eq!(rpn("a = b = 1").unwrap(), "a b 1 = =");
+    assert_eq!(rpn("[(1 + 2)] * 3").unwrap(), "1 2 + 3 *");
+    for bad in ["(1 + 2", "1 + 2)", "(1 + 2]", "1 +", "1 2", "1 * / 2"] {
+        assert!(rpn(bad).is_err(), "{}", bad);
+    }
+}
+
+#[test]
+fn shunting_yard_handles_prefix_operators() {
+    assert_eq!(rpn("-1").unwrap(), "1 -u");
+    assert_eq!(rpn("2 * -3").unwrap(), "2 3 -u *");
+    assert_eq!(rpn("-2 * 3").unwrap(), "2 -u 3 *");
+    assert_eq!(rpn("1 - -2").unwrap(), "1 2 -u -");
+    assert_eq!(rpn("- -a").unwrap(), "a -u -u");
+    assert_eq!(rpn("-(1 + 2)").unwrap(), "1 2 + -u");
+}
+
+#[test]
+fn rpn_evaluates_on_a_stack() {
+    let eval = |src: &str| {
+        let mut lex = calc(src);
+        let tokens = lex.tokenize().unwrap();
+        let rpn = lex.to_rpn(&tokens)?;
+        lex.eval_rpn(&rpn)
+    };
+    assert_eq!(int(&eval("1 + 2 * 3").unwrap()), 7);
+    assert_eq!(int(&eval("(1 + 2) * 3 - 4").unwrap()), 5);
+    assert_eq!(int(&eval("2 * -3").unwrap()), -6);
+    assert_eq!(int(&eval("-2 * 3 - -1").unwrap()), -5);
+    assert_eq!(int(&eval("-(1 + 2)").unwrap()), -3);
+    let mut lex = calc("1 + 2");
+    let tokens = lex.tokenize().unwrap();
+    assert!(lex.eval_rpn(&tokens[1..2]).is_err());
+    assert!(lex.eval_rpn(&tokens[..2]).is_err());
+    assert!(lex.eval_rpn(&[tokens[0].clone(), tokens[2].clone()]).is_err());
+    assert!(lex.eval_rpn(&[]).is_err());
+}
+
+#[test]
+fn operators_in_operand_position_are_rejected() {
+    let sexpr = |src: &str, lenient: bool| {
+        let mut lex = lexer(src);
+        lex.set_lenient_operators(lenient);
+        lex.parser_token().unwrap();
+        lex.parse_expr(&lex.tokenstack).map(|e| e.to_sexpr())
+    };
+    let err = sexpr("a + * b", false).unwrap_err();
+    assert!(format!("{err:?}").contains("start: 4"), "{err:?}");
+    assert!(sexpr("a * / b", false).is_err());
+    assert!(sexpr("a + + b", false).is_err());
+    assert!(sexpr("* b", false).is_err());
+    assert_eq!(sexpr("a + -b", false).unwrap(), "(+ a (- b))");
+    assert_eq!(sexpr("-a * - - b", false).unwrap(), "(* (- a) (- (- b)))");
+    assert_eq!(sexpr("a + * b", true).unwrap(), "(+ a (* b))");
+    let mut lex = lexer("a + * b");
+    lex.set_fixity("lit", "*", true, true, false);
+    lex.parser_token().unwrap();
+    assert_eq!(lex.parse_expr(&lex.tokenstack).unwrap().to_sexpr(), "(+ a (* b))");
+}
+
+#[test]
+fn power_operator_binds_tightest_and_right() {
+    let sexpr = |src: &str, symbol: &'static str| {
+        let mut lex = lexer(src);
+        lex.enable_power_operator(symbol);
+        tree(&mut lex).to_sexpr()
+    };
+    assert_eq!(sexpr("2 * 3 ^ 2", "^"), "(* 2 (^ 3 2))");
+    assert_eq!(sexpr("2 ^ 3 ^ 2", "^"), "(^ 2 (^ 3 2))");
+    assert_eq!(sexpr("-2 ^ 2 + 1", "^"), "(+ (- (^ 2 2)) 1)");
+    assert_eq!(sexpr("2 ** 3 ** 2 * 4", "**"), "(* (** 2 (** 3 2)) 4)");
+    assert_eq!(sexpr("a # b", "#"), "(# a b)");
+    let mut lex = lexer("a # b");
+    lex.enable_power_operator("#");
+    assert!(matches!(lex.literals, std::borrow::Cow::Owned(_)));
+    assert_eq!(lex.literals.matches('#').count(), 1);
+    lex.enable_power_operator("#");
+    assert_eq!(lex.literals.matches('#').count(), 1);
+    let mut lex = calc("2 ^ 3");
+    lex.enable_power_operator("^");
+    assert!(lex.dump_precedence().contains("\n5 right: ^\n"), "{}", lex.dump_precedence());
+    let dump = lex.dump_precedence();
+    lex.enable_power_operator("^");
+    assert_eq!(lex.dump_precedence(), dump);
+    let mut lex = lexer("2 ^ 3 ^ 2 * 2");
+    lex.enable_power_operator("^");
+    lex.enable_power_operator("^");
+    assert_eq!(tree(&mut lex).to_sexpr(), "(* (^ 2 (^ 3 2)) 2)");
+    assert_eq!(lex.precs.iter().filter(|level| level.precs.contains(&"^")).count(), 1);
+    let mut lex = lexer("2 * 3 * 2 + 1");
+    lex.enable_power_operator("*");
+    assert_eq!(tree(&mut lex).to_sexpr(), "(+ (* 2 (* 3 2)) 1)");
+    assert!(lex.dump_precedence().contains("\n3 left: /\n"), "{}", lex.dump_precedence());
+    assert_eq!(lex.dump_precedence().matches('*').count(), 1);
+}
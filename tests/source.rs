@@ -0,0 +1,212 @@
+mod common;
+
+use common::*;
+use lang_ast::{AstAny, AstError, DefaultHandler, IndentKind, IndentStyle, LexToken, Lexer, Preprocessor, SourceMap};
+use regex::Regex;
+use std::sync::Arc;
+
+/// expands each tab to four spaces
+struct Tabs;
+
+impl Preprocessor for Tabs {
+    fn process(&self, src: &str) -> (String, SourceMap) {
+        let mut out = String::new();
+        let mut map = SourceMap::new();
+        for (idx, c) in src.char_indices() {
+            if c == '\t' {
+                map.add_mapping(out.len(), idx);
+                out.push_str("    ");
+                map.add_mapping(out.len(), idx + 1);
+            } else {
+                out.push(c);
+            }
+        }
+        (out, map)
+    }
+}
+
+#[test]
+fn maps_preprocessed_spans_back() {
+    let src = "a\tbb\t\tc";
+    let mut lex = Lexer::new_preprocessed(src.to_string(), DefaultHandler, &Tabs);
+    lex.add_regex("id", Regex::new(r"[a-z]+").unwrap());
+    let tokens = lex.tokenize().unwrap();
+    let spans: Vec<_> = tokens.iter().map(|t| lex.original_span(t)).collect();
+    assert_eq!(spans, vec![(0, 1), (2, 4), (6, 7)]);
+    for (token, (start, end)) in tokens.iter().zip(spans) {
+        assert_eq!(&src[start..end], token.get_value());
+    }
+}
+
+#[test]
+fn lines_around_an_error() {
+    let lex = lexer("one\ntwo\nthree\nfour\nfive");
+    assert_eq!(lex.lines_around(3, 1), vec![(2, "two"), (3, "three"), (4, "four")]);
+    assert_eq!(lex.lines_around(1, 1), vec![(1, "one"), (2, "two")]);
+    assert_eq!(lex.lines_around(5, 2), vec![(3, "three"), (4, "four"), (5, "five")]);
+    assert_eq!(lex.lines_around(9, 1), vec![]);
+    assert_eq!(lex.lines_around(0, 0), vec![]);
+}
+
+#[test]
+fn dominant_indentation_is_detected() {
+    let style = |src: &str| lexer(src).detect_indent();
+    let (tabs, spaces, none) = (IndentKind::Tabs, IndentKind::Spaces, IndentKind::None);
+    assert_eq!(style("a\n\tb\n\t\tc\n\nd"), IndentStyle { kind: tabs, width: 1, mixed: false });
+    assert_eq!(style("a\n  b\n    c\n  \nd"), IndentStyle { kind: spaces, width: 2, mixed: false });
+    assert_eq!(style("a\n  b\n\tc\n    d"), IndentStyle { kind: spaces, width: 2, mixed: true });
+    assert_eq!(style("a\n\t  b"), IndentStyle { kind: tabs, width: 1, mixed: true });
+    assert_eq!(style("a\nb"), IndentStyle { kind: none, width: 0, mixed: false });
+}
+
+#[test]
+fn wide_chars_take_two_columns() {
+    let mut lex = lexer("\"中文\" x \"e\u{301}👍\" y");
+    lex.add_string("\"", "str");
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!((lex.column(tokens[1].start), lex.column(tokens[3].start)), (6, 14));
+    lex.wide_columns = true;
+    assert_eq!((lex.column(tokens[1].start), lex.column(tokens[3].start)), (8, 16));
+}
+
+#[test]
+fn utf16_spans_round_trip() {
+    let src = "a é\u{1F600} bc\n中 d";
+    let plain: Vec<u16> = src.encode_utf16().collect();
+    let marked: Vec<u16> = "\u{FEFF}".encode_utf16().chain(plain.iter().copied()).collect();
+    let (id, other) = (Regex::new("[a-z]+").unwrap(), Regex::new(r"[^\sa-z]+").unwrap());
+    for (units, skip) in [(&plain, 0), (&marked, 1)] {
+        let mut lex = Lexer::from_utf16(units, DefaultHandler).unwrap();
+        assert_eq!(lex.data.as_str(), src);
+        lex.add_regex("id", id.clone());
+        lex.add_regex("x", other.clone());
+        let tokens = lex.tokenize().unwrap();
+        let spans = tokens.iter().map(|t| lex.utf16_span(t)).collect::<Vec<_>>();
+        let want = [(0, 1), (2, 5), (6, 8), (9, 10), (11, 12)].map(|(start, end)| (start + skip, end + skip));
+        assert_eq!(spans, want);
+        for (token, (start, end)) in tokens.iter().zip(&spans) {
+            assert_eq!(String::from_utf16(&units[*start..*end]).unwrap(), token.get_value());
+        }
+        assert_eq!(tokens[0].lsp_position(&lex), (0, 0));
+        // a lexer on the UTF-8 text counts the same units, without the mark
+        assert_eq!(lexer(src).utf16_offset(tokens[2].start) + skip, spans[2].0);
+    }
+    assert!(Lexer::from_utf16(&[0x61, 0xD800, 0x61], DefaultHandler).is_err());
+}
+
+#[test]
+fn lsp_positions_count_utf16_units() {
+    let src = "a\nb \u{1F600} c é d";
+    let mut lex = lexer(src);
+    lex.add_regex("x", Regex::new(r"[^\sa-z]+").unwrap());
+    let tokens = lex.tokenize().unwrap();
+    let found = tokens.iter().map(|t| t.lsp_position(&lex)).collect::<Vec<_>>();
+    assert_eq!(found, vec![(0, 0), (1, 0), (1, 2), (1, 5), (1, 7), (1, 9)]);
+    let units: Vec<u16> = src.encode_utf16().collect();
+    let mut lex = Lexer::from_utf16(&units, DefaultHandler).unwrap();
+    lex.add_regex("id", Regex::new("[a-z]+").unwrap());
+    lex.add_regex("x", Regex::new(r"[^\sa-z]+").unwrap());
+    let tokens = lex.tokenize().unwrap();
+    assert_eq!(tokens.iter().map(|t| t.lsp_position(&lex)).collect::<Vec<_>>(), found);
+}
+
+#[test]
+fn trivia_gives_the_source_back() {
+    let src = "a  =\n\n\n\n(b\t)\n";
+    let mut lex = lexer(src);
+    lex.trivia = true;
+    let flat = lex.tokenize().unwrap();
+    let trivia = flat.iter().filter(|t| t.ty == "trivia").map(|t| (t.get_value(), t.value.clone())).collect::<Vec<_>>();
+    assert_eq!(trivia, [("  ", AstAny::Usize(2)), ("\n\n\n\n", AstAny::Usize(4)), ("\t", AstAny::Usize(1)), ("\n", AstAny::Usize(1))]);
+    assert_eq!(LexToken::to_source(&flat), src);
+    lex.parser_token().unwrap();
+    assert_eq!(LexToken::to_source(&lex.tokenstack), src);
+    let mut lex = lexer("(a");
+    lex.recover_brackets = true;
+    lex.parser_token().unwrap();
+    assert_eq!(LexToken::to_source(&lex.tokenstack), "(a");
+}
+
+#[test]
+fn invalid_utf8_is_reported_where_it_starts() {
+    let mut lex = Lexer::from_bytes("a + é".as_bytes().to_vec(), DefaultHandler).unwrap();
+    lex.add_regex("id", Regex::new(r"\w+").unwrap());
+    assert_eq!(lex.tokenize().unwrap().len(), 3);
+    let err = |bytes: Vec<u8>| format!("{:?}", Lexer::from_bytes(bytes, DefaultHandler).err().unwrap());
+    assert!(err(b"ab \xff cd".to_vec()).contains("InvalidEncoding(3)"));
+    assert!(err(vec![b'x', 0xe6, 0x97]).contains("InvalidEncoding(1)"));
+}
+
+#[test]
+fn right_to_left_lines_are_flagged() {
+    let mut lex = lexer("a = 1\nname = \"שלום\" + b\nx = مرحبا\ny = \u{202E}z\n");
+    lex.strings.clear();
+    let flagged = (0..10).filter(|line| lex.line_has_bidi(*line)).collect::<Vec<_>>();
+    assert_eq!(flagged, vec![2, 3, 4]);
+    // columns stay in logical order
+    let pos = lex.data.find('+').unwrap();
+    assert_eq!(lex.line_col(pos).1, 15);
+    assert_eq!(lex.column(pos), 15);
+}
+
+#[test]
+fn token_text_can_be_cached() {
+    let mut lex = lexer("alpha + beta");
+    let tokens = lex.tokenize().unwrap();
+    let (a, b) = (lex.token_text(&tokens[0]), lex.token_text(&tokens[0]));
+    assert_eq!((&*a, &*b), ("alpha", "alpha"));
+    assert!(!Arc::ptr_eq(&a, &b));
+    lex.text_cache = Some(Default::default());
+    let (a, b) = (lex.token_text(&tokens[2]), lex.token_text(&tokens[2].clone()));
+    assert_eq!(&*a, "beta");
+    assert!(Arc::ptr_eq(&a, &b));
+    assert!(!Arc::ptr_eq(&a, &lex.token_text(&tokens[0])));
+    assert_eq!(lex.text_cache.as_ref().unwrap().len(), 2);
+}
+
+#[test]
+fn long_lines_are_cut_around_the_caret() {
+    let src = format!("x = 1\n{}# + b{}", "a + ".repeat(200), " + c".repeat(100));
+    let mut lex = lexer(&src);
+    let err = lex.tokenize().unwrap_err();
+    let full = lex.render_error(&err);
+    let lines = full.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 3);
+    assert!(lines[0].starts_with("2:801: "), "{}", lines[0]);
+    assert_eq!(lines[1].chars().count(), 1205);
+    assert_eq!(lines[2], format!("{}^", " ".repeat(800)));
+    lex.max_render_width = Some(40);
+    let cut = lex.render_error(&err);
+    let lines = cut.lines().collect::<Vec<_>>();
+    assert!(lines[1].starts_with('…') && lines[1].ends_with('…'), "{}", lines[1]);
+    assert_eq!(lines[1].chars().count(), 42);
+    assert_eq!(lines[1].chars().nth(lines[2].chars().count() - 1), Some('#'));
+
+    let mut short = lexer("ab #");
+    short.max_render_width = Some(40);
+    let err = short.tokenize().unwrap_err();
+    assert_eq!(short.render_error(&err).lines().skip(1).collect::<Vec<_>>(), ["ab #", "   ^"]);
+    let mut end = lexer(&format!("{}#", "a ".repeat(100)));
+    end.max_render_width = Some(10);
+    let err = end.tokenize().unwrap_err();
+    let rendered = end.render_error(&err);
+    let lines = rendered.lines().collect::<Vec<_>>();
+    assert!(lines[1].starts_with('…') && !lines[1].ends_with('…'));
+    assert_eq!(lines[1].chars().nth(lines[2].len() - 1), Some('#'));
+}
+
+#[test]
+fn errors_name_their_file() {
+    let mut lex = Lexer::new_named("a = 1\nb = (c +\n".to_string(), DefaultHandler, "foo.lang");
+    lex.add_regex("id", Regex::new("[a-z]+").unwrap());
+    lex.add_regex("num", Regex::new(r"\d+").unwrap());
+    assert_eq!(lex.name.as_deref(), Some("foo.lang"));
+    let err = lex.parser_token().unwrap_err();
+    let rendered = lex.render_error(&err);
+    assert!(rendered.starts_with("foo.lang:2:5: Not Match close Error"), "{}", rendered);
+    assert_eq!(rendered.lines().nth(2), Some("    ^"));
+    let name: Arc<str> = Arc::from("bar.lang");
+    let lex = Lexer::new_named(String::new(), DefaultHandler, name.clone());
+    assert!(Arc::ptr_eq(lex.name.as_ref().unwrap(), &name));
+    assert_eq!(lex.render_error(&AstError::new_unexpected_eof_error()), "bar.lang: Unexpected eof Error");
+}
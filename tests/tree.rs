@@ -0,0 +1,244 @@
+mod common;
+
+use common::*;
+use lang_ast::{type_histogram, LexPrec, LexToken, ParseNode, TokenTree};
+use std::sync::Arc;
+
+#[test]
+fn navigates_a_flattened_tree() {
+    let mut lex = lexer("(a (b) c)");
+    lex.parser_token().unwrap();
+    let tree = TokenTree::new(&lex.tokenstack);
+    let texts: Vec<_> = (0..tree.len()).map(|idx| tree.get(idx).unwrap().get_value()).collect();
+    assert_eq!(texts, vec!["(", "a", "(", "b", ")", "c", ")"]);
+    let parent = tree.parent(3).unwrap();
+    assert_eq!(parent, 2);
+    assert_eq!(tree.get(tree.next_sibling(parent).unwrap()).unwrap().get_value(), "c");
+    assert_eq!(tree.children(0), &[1, 2, 5, 6]);
+    assert_eq!((tree.next_sibling(0), tree.parent(0)), (None, None));
+}
+
+#[test]
+fn tokens_are_collected_by_type() {
+    let mut lex = lexer("(a + b) * c");
+    lex.parser_token().unwrap();
+    let lits = lex.tokenstack.iter().flat_map(|t| t.collect_type("lit")).map(|t| t.get_value()).collect::<Vec<_>>();
+    assert_eq!(lits, vec!["(", "+", ")", "*"]);
+    let expr = lex.parse_expr(&lex.tokenstack).unwrap();
+    let found = |ty| expr.collect_type(ty).iter().map(|t| t.get_value()).collect::<Vec<_>>();
+    assert_eq!(found("lit"), vec!["+", "*"]);
+    assert_eq!(found("id"), vec!["a", "b", "c"]);
+}
+
+#[test]
+fn fingerprints_ignore_layout() {
+    let prints = |src: &str| {
+        let mut lex = lexer(src);
+        lex.parser_token().unwrap();
+        lex.tokenstack.iter().map(|t| t.fingerprint()).collect::<Vec<_>>()
+    };
+    let base = prints("f (a + [b, c])");
+    assert_eq!(base, prints("f(\n    a+[ b,\n\tc ])"));
+    assert_ne!(base, prints("f (a + [b, d])"));
+    assert_ne!(base[1], prints("g (a + [b c])")[1]);
+    let mut lex = lexer("(a)");
+    lex.parser_token().unwrap();
+    let group = lex.tokenstack[0].clone();
+    let mut retyped = group.clone();
+    retyped.subs[0].set_type("kw");
+    assert_ne!(group.fingerprint(), retyped.fingerprint());
+    assert_eq!(group.fingerprint(), group.clone().fingerprint());
+}
+
+#[test]
+fn tokens_move_onto_a_new_source() {
+    let mut first = lexer("f(x, y)");
+    first.parser_token().unwrap();
+    let mut tokens = lexer("g + h").tokenize().unwrap();
+    let joined = Arc::new(format!("{}\ng + h", first.data));
+    for token in tokens.iter_mut() {
+        token.rebase(joined.clone(), first.data.len() as isize + 1).unwrap();
+    }
+    let mut group = first.tokenstack[1].clone();
+    group.rebase(joined.clone(), 0).unwrap();
+    assert_eq!(group.subs[2].get_value(), "y");
+    assert!(Arc::ptr_eq(&group.subs[2].data, &joined));
+    let found = tokens.iter().map(|t| (t.get_value(), t.lineno)).collect::<Vec<_>>();
+    assert_eq!(found, vec![("g", 2), ("+", 2), ("h", 2)]);
+    // a shift past the end leaves the token as it was
+    let before = tokens[2].clone();
+    assert!(tokens[2].rebase(joined.clone(), 10).is_err());
+    assert_eq!(tokens[2], before);
+    assert!(tokens[0].rebase(joined, -100).is_err());
+}
+
+#[test]
+fn parse_trees_carry_positions() {
+    let mut lex = lexer("(a +\n b)");
+    lex.parser_token().unwrap();
+    let node = ParseNode::new;
+    let want = vec![node("lit", "(", 1, 1, vec![
+        node("id", "a", 1, 2, vec![]),
+        node("lit", "+", 1, 4, vec![]),
+        node("id", "b", 2, 2, vec![]),
+        node("lit", ")", 2, 3, vec![]),
+    ])];
+    assert_eq!(lex.to_parse_tree(), want);
+}
+
+fn mark_ids(tokens: &mut [LexToken]) {
+    for token in tokens {
+        if token.ty == "id" {
+            token.set_flag(LexToken::FLAG_VISITED | LexToken::FLAG_USER, true);
+        }
+        mark_ids(&mut token.subs);
+    }
+}
+
+#[test]
+fn flags_are_bookkeeping() {
+    let mut lex = lexer("(a + (b)) * c");
+    lex.parser_token().unwrap();
+    let mut marked = lex.tokenstack.clone();
+    mark_ids(&mut marked);
+    assert_eq!(marked, lex.tokenstack);
+    assert_eq!(marked[0].fingerprint(), lex.tokenstack[0].fingerprint());
+    let ids = marked[0].collect_type("id");
+    assert_eq!(ids.len(), 2);
+    assert!(ids.iter().all(|t| t.has_flag(LexToken::FLAG_VISITED) && t.has_flag(LexToken::FLAG_USER)));
+    assert!(!marked[0].has_flag(LexToken::FLAG_VISITED));
+    let back = LexToken::from_bytes(&LexToken::to_bytes(&marked), &lex.known_types()).unwrap();
+    assert_eq!(back[0].subs[0].flags, LexToken::FLAG_VISITED | LexToken::FLAG_USER);
+    marked[0].subs[0].set_flag(LexToken::FLAG_VISITED, false);
+    assert_eq!(marked[0].subs[0].flags, LexToken::FLAG_USER);
+    assert_eq!(marked[0].subs[0].clone_base_token().flags, LexToken::FLAG_USER);
+}
+
+#[test]
+fn token_types_are_counted() {
+    let mut lex = lexer("(a + b) * c1 2");
+    lex.parser_token().unwrap();
+    let counts = type_histogram(&lex.tokenstack);
+    assert_eq!((counts.get("id"), counts.get("lit"), counts.get("num")), (Some(&3), Some(&4), Some(&1)));
+    assert_eq!(counts.len(), 3);
+    assert!(type_histogram(&[]).is_empty());
+    let mut lex = calc("1 + x");
+    assert_eq!(type_histogram(&lex.tokenize().unwrap()).len(), 3);
+}
+
+#[test]
+fn canonical_forms_compare_equal() {
+    let parse = |src: &str| {
+        let mut lex = lexer(src);
+        lex.add_literal("&&");
+        lex.set_precs(vec![
+            LexPrec::new("lit", true, vec!["&&"]),
+            LexPrec::new("lit", true, vec!["+", "-"]),
+            LexPrec::new("lit", true, vec!["*"]),
+        ]);
+        lex.add_operator_alias("and", "&&");
+        lex.parser_token().unwrap();
+        let tree = lex.parse_expr(&lex.tokenstack).unwrap();
+        (lex, tree)
+    };
+    let (lex, a) = parse("a and b");
+    let (_, b) = parse("a   &&\n b");
+    assert_ne!(a, b);
+    let canon = lex.canonicalize(&a, &[]);
+    assert_eq!(canon, lex.canonicalize(&b, &[]));
+    assert_eq!(canon.get_value(), "&&");
+    assert_eq!(canon.data.as_str(), "&& a b");
+    assert_eq!(canon.to_sexpr(), "(&& a b)");
+    // commutative operators sort their operands
+    let (_, x) = parse("c * (b + a) and y");
+    let (_, y) = parse("y && (a + b) * c");
+    assert_ne!(lex.canonicalize(&x, &[]), lex.canonicalize(&y, &[]));
+    assert_eq!(lex.canonicalize(&x, &["&&", "+", "*"]), lex.canonicalize(&y, &["&&", "+", "*"]));
+    let (_, m) = parse("a - b");
+    let (_, n) = parse("b - a");
+    assert_ne!(lex.canonicalize(&m, &["+", "*"]), lex.canonicalize(&n, &["+", "*"]));
+    // long chains, reordered or not, are walked without recursing
+    let n = 10_000;
+    let src = vec!["x"; n].join(" + ");
+    let (lex, x) = parse(&src);
+    let canon = lex.canonicalize(&x, &[]);
+    assert_eq!(canon.data.as_str(), format!("{}x{}", "+ ".repeat(n - 1), " x".repeat(n - 1)));
+    assert_eq!(canon.to_sexpr(), x.to_sexpr());
+    let (_, y) = parse(&format!("{} + y", src));
+    let (_, z) = parse(&format!("y + {}", src));
+    let canon = |tree: &LexToken, commutative: &[&str]| lex.canonicalize(tree, commutative).data.to_string();
+    assert_eq!(canon(&y, &["+"]), canon(&y, &["+"]));
+    assert_ne!(canon(&y, &[]), canon(&z, &[]));
+}
+
+#[test]
+fn full_spans_reach_the_closing_bracket() {
+    let mut lex = lexer("x * (a + [b]) - c");
+    lex.parser_token().unwrap();
+    let group = &lex.tokenstack[2];
+    assert_eq!(group.get_value(), "(");
+    assert_eq!(group.full_span(), (4, 13));
+    assert_eq!(&lex.data[4..13], "(a + [b])");
+    assert_eq!(group.subs[2].full_span(), (9, 12));
+    assert_eq!(lex.tokenstack[0].full_span(), (0, 1));
+    assert_eq!(tree(&mut lexer("x * (a + [b]) - c")).full_span(), (0, 17));
+    let mut lex = lexer("(a");
+    lex.recover_brackets = true;
+    lex.parser_token().unwrap();
+    assert_eq!(lex.tokenstack[0].full_span(), (0, 2));
+}
+
+#[test]
+fn group_structure_is_validated() {
+    let mut lex = lexer("f(a, [b + (c)]) { d }");
+    lex.parser_token().unwrap();
+    for token in &lex.tokenstack {
+        token.validate_structure(&lex).unwrap();
+    }
+    let mut bad = lex.tokenstack[1].clone();
+    bad.subs.pop();
+    assert_eq!(kind(&bad.validate_structure(&lex).unwrap_err()), "NoMatchClose");
+    let mut bad = lex.tokenstack[1].clone();
+    let closer = bad.subs.pop().unwrap();
+    bad.subs.insert(0, closer.clone());
+    bad.subs.push(closer);
+    assert_eq!(kind(&bad.validate_structure(&lex).unwrap_err()), "UnexpectedToken");
+    let mut bad = lex.tokenstack[1].clone();
+    let inner = bad.subs[2].subs.pop().unwrap();
+    assert_eq!(inner.get_value(), "]");
+    bad.subs[2].subs.push(lex.tokenstack[2].subs.last().unwrap().clone());
+    assert!(bad.validate_structure(&lex).is_err());
+    let mut lex = lexer("(a");
+    lex.recover_brackets = true;
+    lex.parser_token().unwrap();
+    assert!(lex.tokenstack[0].validate_structure(&lex).is_ok());
+}
+
+#[test]
+fn deep_groups_are_walked_without_recursing() {
+    let n = 10_000;
+    let mut lex = lexer(&format!("{}a{}", "(".repeat(n), ")".repeat(n)));
+    lex.parser_token().unwrap();
+    let mut seen = 0;
+    lex.contextual_rewrite(|prev, cur, next| {
+        if cur.get_value() == "a" {
+            assert!(prev.is_none() && next.is_some_and(|t| t.get_value() == ")"));
+            cur.set_type("name");
+        }
+        seen += 1;
+    });
+    assert_eq!(seen, 2 * n + 1);
+    assert_eq!(lex.tokenstack[0].full_span(), (0, 2 * n + 1));
+    let mut nodes = lex.to_parse_tree();
+    let mut depth = 0;
+    while let Some(mut node) = nodes.pop() {
+        assert_eq!(node.children.len(), if depth < n { 2 } else { 0 });
+        node.children.pop();
+        depth += 1;
+        nodes = node.children;
+        if depth == n + 1 {
+            assert_eq!((node.ty, node.text.as_str(), node.col), ("name", "a", n + 1));
+        }
+    }
+    assert_eq!(depth, n + 1);
+}